@@ -1,3 +1,4 @@
+use clap::{Parser, ValueEnum};
 pub use db_client::*;
 use dotenv::dotenv;
 use futures::stream::{self, StreamExt};
@@ -7,6 +8,8 @@ use std::env;
 use std::error::Error;
 use std::sync::Arc;
 use tokio::sync::{Mutex, Semaphore};
+use tracing::{error, info, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
 
 mod db_client;
 
@@ -59,9 +62,40 @@ async fn get_transaction_info(
     })
 }
 
+// The same logging flags as liquidations_aggregator
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Log format, json writes one JSON object per line
+    #[arg(long, value_enum, default_value_t = LogFormat::Human)]
+    log_format: LogFormat,
+    /// Level or filter directives like `info,gas_price_filler=debug`, RUST_LOG takes precedence
+    #[arg(long, default_value = "info")]
+    log_level: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Human,
+    Json,
+}
+
+fn init_logging(format: LogFormat, level: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        LogFormat::Human => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).init(),
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
     dotenv().ok();
+    init_logging(cli.log_format, &cli.log_level);
 
     let rpc_url = &env::var("RPC_URL").expect("RPC_URL must be set in .env file");
 
@@ -70,12 +104,12 @@ async fn main() {
     let txs = match fetch_transactions(pool.clone()).await {
         Ok(data) => data,
         Err(e) => {
-            eprintln!("Error fetching transactions: {}", e);
+            error!("Error fetching transactions: {}", e);
             return;
         }
     };
 
-    println!("Starting to analyze {} transactions", txs.len());
+    info!("Starting to analyze {} transactions", txs.len());
 
     let semaphore = Arc::new(Semaphore::new(64));
 
@@ -83,11 +117,12 @@ async fn main() {
         .for_each_concurrent(Some(64), |tx| {
             let semaphore = Arc::clone(&semaphore);
             let pool_clone = pool.clone();
+            let span = info_span!("transaction", tx = %tx);
             async move {
                 let _permit = match semaphore.acquire().await {
                     Ok(permit) => permit,
                     Err(e) => {
-                        eprintln!("Error acquiring semaphore: {}", e);
+                        error!("Error acquiring semaphore: {}", e);
                         return;
                     }
                 };
@@ -95,22 +130,27 @@ async fn main() {
                 let tx_info = match get_transaction_info(&tx, &rpc_url).await {
                     Ok(logs) => logs,
                     Err(e) => {
-                        eprintln!("Error getting transction info {}: {}", tx, e);
+                        error!("Error getting transaction info: {}", e);
                         return;
                     }
                 };
 
-                println!("Tx info {:?}", &tx_info);
+                info!(
+                    gas_price = tx_info.gas_price,
+                    data_prefix = %tx_info.data_prefix,
+                    "Got transaction info"
+                );
 
                 if let Err(e) =
                     insert_into_db(pool_clone, &tx, tx_info.gas_price, &tx_info.data_prefix).await
                 {
-                    eprintln!("Error inserting data into database for {}: {}", tx, e);
+                    error!("Error inserting data into database: {}", e);
                     return;
                 }
 
-                println!("Data inserted for {}", tx);
+                info!("Data inserted");
             }
+            .instrument(span)
         })
         .await;
 }
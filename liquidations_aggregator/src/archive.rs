@@ -0,0 +1,348 @@
+use {
+    crate::{failures::*, forge::*, progress::*},
+    flate2::{read::GzDecoder, write::GzEncoder, Compression},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        error::Error as StdError,
+        fs::{self, File, OpenOptions},
+        io::{self, Read, Write},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tracing::{info, warn},
+};
+
+const LOGS_DIR: &str = "logs";
+const MANIFEST: &str = "manifest.jsonl";
+// `single` archives into this run, it is never pruned nor counted towards keep_runs
+pub const SINGLE_RUN_ID: &str = "single";
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    // Logs of older runs are deleted at the start of a run, 0 keeps all of them
+    pub keep_runs: usize,
+    pub max_age_days: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            enabled: true,
+            keep_runs: 20,
+            max_age_days: 90,
+        }
+    }
+}
+
+// One line of runs/<run id>/manifest.jsonl, paths are relative to the run directory
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArchiveEntry {
+    pub run_id: String,
+    pub transaction_hash: String,
+    pub attempt: u32,
+    pub outcome: String, // "success" or the failure category
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    // Archives from before the results file have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results_json: Option<PathBuf>,
+    pub stdout_bytes: usize,
+    pub stderr_bytes: usize,
+    pub archived_at: u64, // Unix seconds
+}
+
+// Keeps the complete stdout and stderr of every forge run as gzip files in runs/<run id>/logs
+#[derive(Clone)]
+pub struct LogArchive {
+    run_id: String,
+    dir: PathBuf,
+    manifest: Arc<Mutex<File>>,
+}
+
+impl LogArchive {
+    pub fn create(run_id: &str) -> Result<Self, Box<dyn StdError>> {
+        let dir = run_dir(run_id);
+        fs::create_dir_all(dir.join(LOGS_DIR))?;
+        let manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(MANIFEST))?;
+
+        Ok(LogArchive {
+            run_id: run_id.to_string(),
+            dir,
+            manifest: Arc::new(Mutex::new(manifest)),
+        })
+    }
+
+    // Errors are logged, a full disk should not fail the test itself
+    pub async fn store(
+        &self,
+        transaction_hash: &str,
+        attempt: u32,
+        result: &Result<ForgeOutput, ForgeFailure>,
+    ) {
+        let (output, outcome) = match result {
+            Ok(output) => (output.clone(), "success"),
+            Err(failure) => (
+                ForgeOutput {
+                    stdout: failure.stdout.clone(),
+                    stdout_file: failure.stdout_file.clone(),
+                    stderr: failure.stderr.clone(),
+                    results_json: None,
+                    parsed: None,
+                },
+                failure.category.as_str(),
+            ),
+        };
+        if output.stdout.is_empty() && output.stderr.is_empty() {
+            return; // forge never started
+        }
+
+        let archive = self.clone();
+        let transaction_hash = transaction_hash.to_string();
+        let outcome = outcome.to_string();
+        let stored = tokio::task::spawn_blocking(move || {
+            archive
+                .write(&transaction_hash, attempt, &output, outcome)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+
+        match stored {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Error archiving forge output: {}", e),
+            Err(e) => warn!("Error archiving forge output: {}", e),
+        }
+    }
+
+    fn write(
+        &self,
+        transaction_hash: &str,
+        attempt: u32,
+        output: &ForgeOutput,
+        outcome: String,
+    ) -> Result<(), Box<dyn StdError>> {
+        let name = |stream: &str| {
+            Path::new(LOGS_DIR).join(format!("{}.{}.{}.gz", transaction_hash, attempt, stream))
+        };
+        let mut entry = ArchiveEntry {
+            run_id: self.run_id.clone(),
+            transaction_hash: transaction_hash.to_string(),
+            attempt,
+            outcome,
+            stdout: name("stdout"),
+            stderr: name("stderr"),
+            results_json: output.results_json.as_ref().map(|_| name("results.json")),
+            stdout_bytes: output.stdout.len(),
+            stderr_bytes: output.stderr.len(),
+            archived_at: unix_now(),
+        };
+        // A live forge run has its complete stdout in a file, not in memory
+        match &output.stdout_file {
+            Some(file) => {
+                entry.stdout_bytes = copy_gz(file.path(), &self.dir.join(&entry.stdout))? as usize
+            }
+            None => write_gz(&self.dir.join(&entry.stdout), &output.stdout)?,
+        }
+        write_gz(&self.dir.join(&entry.stderr), &output.stderr)?;
+        if let (Some(path), Some(results_json)) = (&entry.results_json, &output.results_json) {
+            write_gz(&self.dir.join(path), results_json)?;
+        }
+
+        let line = serde_json::to_string(&entry)?;
+        writeln!(self.manifest.lock().unwrap(), "{}", line)?;
+        Ok(())
+    }
+}
+
+fn write_gz(path: &Path, content: &str) -> Result<(), Box<dyn StdError>> {
+    let mut encoder = GzEncoder::new(File::create(path)?, Compression::default());
+    encoder.write_all(content.as_bytes())?;
+    encoder.finish()?;
+    Ok(())
+}
+
+fn copy_gz(from: &Path, to: &Path) -> Result<u64, Box<dyn StdError>> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    let bytes = io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(bytes)
+}
+
+pub fn read_gz(path: &Path) -> Result<String, Box<dyn StdError>> {
+    let mut content = String::new();
+    GzDecoder::new(File::open(path)?).read_to_string(&mut content)?;
+    Ok(content)
+}
+
+// Entries of every archived run, oldest run first
+pub fn read_manifests() -> Result<Vec<(PathBuf, ArchiveEntry)>, Box<dyn StdError>> {
+    let mut entries = Vec::new();
+    for run_id in archived_runs()? {
+        let dir = run_dir(&run_id);
+        let content = fs::read_to_string(dir.join(MANIFEST))?;
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            entries.push((dir.clone(), serde_json::from_str(line)?));
+        }
+    }
+    Ok(entries)
+}
+
+// The latest archived forge output of each transaction that forge completed, all of them
+// when no transaction is given. Parse errors are included, a parser fix is what they wait for.
+pub fn latest_archived_outputs(
+    transaction_hashes: &[String],
+) -> Result<Vec<(PathBuf, ArchiveEntry)>, Box<dyn StdError>> {
+    let mut latest: BTreeMap<String, (PathBuf, ArchiveEntry)> = BTreeMap::new();
+    for (dir, entry) in read_manifests()? {
+        let completed =
+            entry.outcome == "success" || entry.outcome == FailureCategory::ParseError.as_str();
+        let wanted =
+            transaction_hashes.is_empty() || transaction_hashes.contains(&entry.transaction_hash);
+        // The single run sorts before every other run, its outputs can still be the latest
+        let newer = latest
+            .get(&entry.transaction_hash)
+            .is_none_or(|(_, stored)| stored.archived_at <= entry.archived_at);
+        if completed && wanted && newer {
+            latest.insert(entry.transaction_hash.clone(), (dir, entry));
+        }
+    }
+    Ok(latest.into_values().collect())
+}
+
+pub fn read_output(dir: &Path, entry: &ArchiveEntry) -> Result<ForgeOutput, Box<dyn StdError>> {
+    Ok(ForgeOutput {
+        stdout: read_gz(&dir.join(&entry.stdout))?,
+        stdout_file: None,
+        stderr: read_gz(&dir.join(&entry.stderr))?,
+        results_json: match &entry.results_json {
+            Some(path) => Some(read_gz(&dir.join(path))?),
+            None => None,
+        },
+        parsed: None,
+    })
+}
+
+// Runs with a manifest, sorted by the time in their id
+fn archived_runs() -> Result<Vec<String>, Box<dyn StdError>> {
+    let runs_dir = Path::new(RUNS_DIR);
+    if !runs_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut runs: Vec<String> = fs::read_dir(runs_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(MANIFEST).exists())
+        .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+        .collect();
+    runs.sort_by_key(|run_id| run_started_at(run_id));
+    Ok(runs)
+}
+
+// Run ids start with the unix time they were created at, see new_run_id
+fn run_started_at(run_id: &str) -> u64 {
+    run_id
+        .split('-')
+        .next()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(0)
+}
+
+fn runs_to_prune(runs: &[String], current: &str, config: &ArchiveConfig, now: u64) -> Vec<String> {
+    let max_age_secs = config.max_age_days * 24 * 60 * 60;
+    let mut older: Vec<&String> = runs
+        .iter()
+        .filter(|run_id| *run_id != current && *run_id != SINGLE_RUN_ID)
+        .collect();
+    older.sort_by_key(|run_id| std::cmp::Reverse(run_started_at(run_id)));
+
+    older
+        .into_iter()
+        .enumerate()
+        .filter(|(i, run_id)| {
+            let too_many = config.keep_runs > 0 && *i + 1 >= config.keep_runs;
+            let too_old = config.max_age_days > 0
+                && now.saturating_sub(run_started_at(run_id)) > max_age_secs;
+            too_many || too_old
+        })
+        .map(|(_, run_id)| run_id.clone())
+        .collect()
+}
+
+// Deletes the logs and manifests of runs past the retention, their summaries are kept
+pub fn prune_archive(current: &str, config: &ArchiveConfig) {
+    let runs = match archived_runs() {
+        Ok(runs) => runs,
+        Err(e) => {
+            warn!("Error listing archived runs: {}", e);
+            return;
+        }
+    };
+
+    for run_id in runs_to_prune(&runs, current, config, unix_now()) {
+        let dir = run_dir(&run_id);
+        let removed = fs::remove_dir_all(dir.join(LOGS_DIR))
+            .or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+            .and_then(|_| fs::remove_file(dir.join(MANIFEST)));
+        match removed {
+            Ok(()) => info!(run_id, "Pruned archived forge logs"),
+            Err(e) => warn!(run_id, "Error pruning archived forge logs: {}", e),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_to_prune() {
+        let day = 24 * 60 * 60;
+        let now = 100 * day;
+        let mut runs: Vec<String> = [1, 95, 97, 98, 99]
+            .iter()
+            .map(|days| format!("{}-42", days * day))
+            .collect();
+        runs.push(SINGLE_RUN_ID.to_string());
+        let config = ArchiveConfig {
+            enabled: true,
+            keep_runs: 3,
+            max_age_days: 30,
+        };
+
+        // The current run counts towards keep_runs but is never pruned
+        let current = &runs[4];
+        let pruned = runs_to_prune(&runs, current, &config, now);
+        assert_eq!(pruned, vec![runs[1].clone(), runs[0].clone()]);
+
+        let keep_all = ArchiveConfig {
+            keep_runs: 0,
+            max_age_days: 0,
+            ..config
+        };
+        assert!(runs_to_prune(&runs, current, &keep_all, now).is_empty());
+    }
+
+    #[test]
+    fn test_gz_roundtrip() {
+        let path = std::env::temp_dir().join(format!("archive-test-{}.gz", std::process::id()));
+        write_gz(&path, "Tests case: repeatLiquidation\n").unwrap();
+        assert_eq!(read_gz(&path).unwrap(), "Tests case: repeatLiquidation\n");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,4 +1,10 @@
+use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::str::FromStr;
 ///! 128 and 256 bit numbers
 ///! U128 is more efficient that u128
 ///! https://github.com/solana-labs/solana/issues/19549
@@ -17,6 +23,224 @@ impl Serialize for U256 {
     }
 }
 
+// Decimal strings like they are serialized, JSON numbers lose precision above 2^53
+impl<'de> Deserialize<'de> for U256 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let dec_str = String::deserialize(deserializer)?;
+        U256::from_dec_str(&dec_str).map_err(de::Error::custom)
+    }
+}
+
+const DECIMALS: usize = 18;
+
+// Signed fixed-point number with 18 decimals, what the test logs USD values and health
+// factors as. Displayed with all 18 decimals, like the test prints them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Decimal18 {
+    negative: bool,  // Never set for zero
+    magnitude: U256, // The absolute value * 10^18
+}
+
+impl Decimal18 {
+    fn new(negative: bool, magnitude: U256) -> Self {
+        Decimal18 {
+            negative: negative && !magnitude.is_zero(),
+            magnitude,
+        }
+    }
+
+    // From the value * 10^18, like the test computes USD values before logging them
+    pub fn from_wei(wei: U256) -> Self {
+        Decimal18::new(false, wei)
+    }
+
+    pub fn abs(self) -> Self {
+        Decimal18::new(false, self.magnitude)
+    }
+
+    fn one() -> U256 {
+        U256::exp10(DECIMALS)
+    }
+
+    // The operators panic on overflow like U256, these return None instead
+    pub fn checked_add(self, other: Decimal18) -> Option<Decimal18> {
+        if self.negative == other.negative {
+            let magnitude = self.magnitude.checked_add(other.magnitude)?;
+            Some(Decimal18::new(self.negative, magnitude))
+        } else {
+            Some(self + other)
+        }
+    }
+
+    pub fn checked_sub(self, other: Decimal18) -> Option<Decimal18> {
+        self.checked_add(-other)
+    }
+
+    // Rounds towards zero like the test does
+    pub fn checked_mul(self, other: Decimal18) -> Option<Decimal18> {
+        let magnitude = self.magnitude.checked_mul(other.magnitude)? / Decimal18::one();
+        Some(Decimal18::new(self.negative != other.negative, magnitude))
+    }
+
+    // Rounds towards zero, None on division by zero
+    pub fn checked_div(self, other: Decimal18) -> Option<Decimal18> {
+        if other.magnitude.is_zero() {
+            return None;
+        }
+        let magnitude = self.magnitude.checked_mul(Decimal18::one())? / other.magnitude;
+        Some(Decimal18::new(self.negative != other.negative, magnitude))
+    }
+}
+
+impl FromStr for Decimal18 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` is not a decimal number", s);
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, s),
+        };
+        let (integer, digits) = match unsigned.split_once('.') {
+            Some((integer, digits)) if !digits.is_empty() => (integer, digits),
+            Some(_) => return Err(invalid()),
+            None => (unsigned, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if integer.is_empty() || !is_digits(integer) || !is_digits(digits) {
+            return Err(invalid());
+        }
+        if digits.len() > DECIMALS {
+            return Err(format!("`{}` has more than {} decimals", s, DECIMALS));
+        }
+
+        let integer = U256::from_dec_str(integer).map_err(|_| invalid())?;
+        let fraction = match digits {
+            "" => U256::zero(),
+            _ => {
+                U256::from_dec_str(digits).map_err(|_| invalid())?
+                    * U256::exp10(DECIMALS - digits.len())
+            }
+        };
+        let magnitude = integer
+            .checked_mul(Decimal18::one())
+            .and_then(|integer| integer.checked_add(fraction))
+            .ok_or_else(|| format!("`{}` is out of range", s))?;
+        Ok(Decimal18::new(negative, magnitude))
+    }
+}
+
+impl fmt::Display for Decimal18 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.negative { "-" } else { "" };
+        let integer = self.magnitude / Decimal18::one();
+        let fraction = (self.magnitude % Decimal18::one()).to_string();
+        write!(
+            f,
+            "{}{}.{:0>width$}",
+            sign,
+            integer,
+            fraction,
+            width = DECIMALS
+        )
+    }
+}
+
+impl Ord for Decimal18 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.magnitude.cmp(&other.magnitude),
+            (true, true) => other.magnitude.cmp(&self.magnitude),
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Decimal18 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for Decimal18 {
+    type Output = Decimal18;
+
+    fn neg(self) -> Decimal18 {
+        Decimal18::new(!self.negative, self.magnitude)
+    }
+}
+
+impl Add for Decimal18 {
+    type Output = Decimal18;
+
+    fn add(self, other: Decimal18) -> Decimal18 {
+        if self.negative == other.negative {
+            Decimal18::new(self.negative, self.magnitude + other.magnitude)
+        } else if self.magnitude >= other.magnitude {
+            Decimal18::new(self.negative, self.magnitude - other.magnitude)
+        } else {
+            Decimal18::new(other.negative, other.magnitude - self.magnitude)
+        }
+    }
+}
+
+impl Sub for Decimal18 {
+    type Output = Decimal18;
+
+    fn sub(self, other: Decimal18) -> Decimal18 {
+        self + -other
+    }
+}
+
+// Panics when the product of the magnitudes overflows U256, above about 2^128 each
+impl Mul for Decimal18 {
+    type Output = Decimal18;
+
+    fn mul(self, other: Decimal18) -> Decimal18 {
+        self.checked_mul(other)
+            .expect("Decimal18 multiplication overflow")
+    }
+}
+
+// Panics on division by zero and when the dividend is above about 2^196
+impl Div for Decimal18 {
+    type Output = Decimal18;
+
+    fn div(self, other: Decimal18) -> Decimal18 {
+        self.checked_div(other)
+            .expect("Decimal18 division overflow or by zero")
+    }
+}
+
+impl Sum for Decimal18 {
+    fn sum<I: Iterator<Item = Decimal18>>(iter: I) -> Decimal18 {
+        iter.fold(Decimal18::default(), Add::add)
+    }
+}
+
+impl Serialize for Decimal18 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal18 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let dec_str = String::deserialize(deserializer)?;
+        dec_str.parse().map_err(de::Error::custom)
+    }
+}
+
 #[macro_export]
 macro_rules! construct_bignum {
     ( $(#[$attr:meta])* $visibility:vis struct $name:ident ( $n_words:tt ); ) => {
@@ -342,3 +566,68 @@ macro_rules! construct_bignum {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimal18() {
+        let parse = |s: &str| s.parse::<Decimal18>().unwrap();
+
+        assert_eq!(
+            parse("93.895365798778689364").to_string(),
+            "93.895365798778689364"
+        );
+        assert_eq!(parse("-1.5").to_string(), "-1.500000000000000000");
+        assert_eq!(parse("-0").to_string(), "0.000000000000000000");
+        assert_eq!(parse("7").to_string(), "7.000000000000000000");
+        for invalid in [
+            "",
+            "-",
+            ".5",
+            "1.",
+            "1.2.3",
+            "1e18",
+            "0.0000000000000000001",
+        ] {
+            assert!(invalid.parse::<Decimal18>().is_err(), "{}", invalid);
+        }
+
+        // Profit can be negative
+        let repaid = parse("961.382617836595450636");
+        let seized = parse("1057.520879410374140000");
+        let gas = parse("2.242895775000000000");
+        assert_eq!(seized - repaid - gas, parse("93.895365798778689364"));
+        assert_eq!(repaid - seized, parse("-96.138261573778689364"));
+        assert_eq!(
+            [repaid, -seized, gas].into_iter().sum::<Decimal18>(),
+            parse("-93.895365798778689364")
+        );
+        assert_eq!(parse("-1.5") * parse("2.5"), parse("-3.75"));
+        assert_eq!(parse("1") / parse("-3"), parse("-0.333333333333333333"));
+
+        let max = Decimal18::from_wei(U256::MAX);
+        assert_eq!(max.checked_add(parse("1")), None);
+        assert_eq!(max.checked_add(parse("-1")), Some(max - parse("1")));
+        assert_eq!((-max).checked_sub(parse("1")), None);
+        assert_eq!(max.checked_mul(parse("2")), None);
+        assert_eq!(
+            parse("-1.5").checked_mul(parse("2.5")),
+            Some(parse("-3.75"))
+        );
+        assert_eq!(max.checked_div(parse("2")), None);
+        assert_eq!(parse("1").checked_div(parse("0")), None);
+
+        let mut values = vec![parse("1"), parse("-2"), parse("0"), parse("-1.5")];
+        values.sort();
+        assert_eq!(values, [parse("-2"), parse("-1.5"), parse("0"), parse("1")]);
+
+        let json = serde_json::to_string(&parse("-0.5")).unwrap();
+        assert_eq!(json, "\"-0.500000000000000000\"");
+        assert_eq!(
+            serde_json::from_str::<Decimal18>(&json).unwrap(),
+            parse("-0.5")
+        );
+    }
+}
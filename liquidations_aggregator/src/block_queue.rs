@@ -0,0 +1,87 @@
+use {
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    },
+    tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+};
+
+// Liquidations of the same block fork the same state, running them at the same time
+// only doubles the RPC load. Their tests wait for each other in lease order instead.
+#[derive(Default)]
+pub struct BlockQueue {
+    blocks: Mutex<HashMap<i64, Arc<AsyncMutex<()>>>>,
+}
+
+// Holds the block until dropped, on every path out of a test
+pub struct BlockGuard<'a> {
+    queue: &'a BlockQueue,
+    block_number: i64,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl BlockQueue {
+    // Returns the guard and whether another test of the block had to finish first
+    pub async fn lock(&self, block_number: i64) -> (BlockGuard<'_>, bool) {
+        let lock = Arc::clone(self.blocks.lock().unwrap().entry(block_number).or_default());
+
+        let (guard, waited) = match Arc::clone(&lock).try_lock_owned() {
+            Ok(guard) => (guard, false),
+            Err(_) => (lock.lock_owned().await, true),
+        };
+
+        let guard = BlockGuard {
+            queue: self,
+            block_number,
+            guard: Some(guard),
+        };
+        (guard, waited)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+}
+
+impl Drop for BlockGuard<'_> {
+    fn drop(&mut self) {
+        let mut blocks = self.queue.blocks.lock().unwrap();
+        drop(self.guard.take());
+        // Only the map references the lock once nobody holds or waits for it
+        if blocks
+            .get(&self.block_number)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            blocks.remove(&self.block_number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    #[tokio::test]
+    async fn test_same_block_runs_in_order() {
+        let queue = BlockQueue::default();
+
+        let (first, waited) = queue.lock(1).await;
+        assert!(!waited);
+        let (_other, waited) = queue.lock(2).await;
+        assert!(!waited);
+
+        let second = queue.lock(1);
+        tokio::pin!(second);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut second)
+            .await
+            .is_err());
+
+        // A failed test releases its block like a successful one
+        drop(first);
+        let (second, waited) = second.await;
+        assert!(waited);
+        drop(second);
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+use {
+    crate::{logging::*, reparse::*, simulator::*},
+    clap::{Args, Parser, Subcommand},
+    std::{net::SocketAddr, path::PathBuf},
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Replays Venus liquidations through the forge strategy tests")]
+pub struct Cli {
+    /// TOML file with the aggregator settings
+    #[arg(long, global = true, default_value = "aggregator.toml")]
+    pub config: PathBuf,
+    /// TOML file describing which liquidations to test
+    #[arg(long, global = true, default_value = "selection.toml")]
+    pub selection: PathBuf,
+    /// Log format, json writes one JSON object per line
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Human)]
+    pub log_format: LogFormat,
+    /// Level or filter directives like `info,liquidations_aggregator::forge=debug`, RUST_LOG takes precedence
+    #[arg(long, global = true, default_value = "info")]
+    pub log_level: String,
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Queue the selected liquidations that have no results yet and test the queued ones
+    Run(RunArgs),
+    /// Test a single liquidation transaction
+    Single {
+        tx_hash: String,
+        /// Print the parsed results instead of inserting them
+        #[arg(long)]
+        no_insert: bool,
+        /// Replay the archived forge output instead of running forge
+        #[arg(long, value_enum, default_value_t = SimulatorKind::Forge)]
+        simulator: SimulatorKind,
+    },
+    /// Print the liquidations `run` would test, without running forge
+    List {
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Explain the selection: what gets tested, what is excluded and by which rule
+    Plan {
+        /// Write the selected liquidations to this CSV file
+        #[arg(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Parse forge output again, update the results and print what changed
+    Reparse {
+        /// Only reparse these transactions (all archived or tested ones by default)
+        tx_hashes: Vec<String>,
+        #[arg(long, default_value_t = 16)]
+        workers: usize,
+        /// Archived forge logs, or the raw strategy lines stored with the results
+        #[arg(long, value_enum, default_value_t = ReparseSource::Archive)]
+        source: ReparseSource,
+        /// Print the diff without updating the results
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print how many liquidations are tested and how many are left
+    Stats,
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Most forge tests running at the same time, overrides `max` in [concurrency]
+    #[arg(long)]
+    pub workers: Option<usize>,
+    /// Stop after this many liquidations
+    #[arg(long)]
+    pub limit: Option<usize>,
+    /// Only work on the jobs already in the queue, e.g. on a second machine
+    #[arg(long)]
+    pub no_enqueue: bool,
+    /// Put failed jobs back into the queue
+    #[arg(long)]
+    pub retry_failed: bool,
+    /// Serve Prometheus metrics on http://<addr>/metrics, e.g. 0.0.0.0:9184
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Replay the archived forge output instead of running forge
+    #[arg(long, value_enum, default_value_t = SimulatorKind::Forge)]
+    pub simulator: SimulatorKind,
+}
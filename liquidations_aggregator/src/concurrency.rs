@@ -0,0 +1,324 @@
+use {
+    crate::{failures::*, memory::*, metrics::*},
+    serde::Deserialize,
+    std::{
+        collections::VecDeque,
+        future::Future,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+    sysinfo::System,
+    tokio::sync::{OwnedSemaphorePermit, Semaphore},
+    tracing::{info, warn},
+};
+
+// Forge attempts older than this don't count towards the RPC error rate
+const RPC_ERROR_WINDOW: Duration = Duration::from_secs(5 * 60);
+// Fewer attempts than this in the window say nothing about the RPC
+const RPC_ERROR_MIN_SAMPLES: usize = 5;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    pub min: usize,
+    pub max: usize,
+    pub initial: usize,
+    pub adjust_interval_secs: u64,
+    // Free memory needed on top of the pressure thresholds before adding a worker
+    pub memory_per_worker_gb: f64,
+    // 1 minute load average divided by the number of CPUs
+    pub max_load_per_cpu: f64,
+    // Share of forge attempts failing with rpc_rate_limit or fork_fetch_error
+    pub max_rpc_error_rate: f64,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            min: 1,
+            max: 16,
+            initial: 4,
+            adjust_interval_secs: 15,
+            memory_per_worker_gb: 4.0,
+            max_load_per_cpu: 1.0,
+            max_rpc_error_rate: 0.2,
+        }
+    }
+}
+
+// Limits the number of concurrent forge tests to a target that follows the load of the
+// machine and the RPC. The semaphore has `max` permits, the ones above the target are parked.
+pub struct ConcurrencyController {
+    config: ConcurrencyConfig,
+    semaphore: Arc<Semaphore>,
+    parked: Mutex<Vec<OwnedSemaphorePermit>>,
+    target: Mutex<usize>,
+    attempts: Mutex<VecDeque<(Instant, bool)>>, // (when, was an RPC error)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Adjustment {
+    Increase,
+    Decrease,
+    Hold,
+}
+
+impl ConcurrencyController {
+    pub fn new(mut config: ConcurrencyConfig) -> Self {
+        config.max = config.max.max(1);
+        config.min = config.min.clamp(1, config.max);
+        config.initial = config.initial.clamp(config.min, config.max);
+
+        let semaphore = Arc::new(Semaphore::new(config.max));
+        let parked = (config.initial..config.max)
+            .map(|_| Arc::clone(&semaphore).try_acquire_owned().unwrap())
+            .collect();
+
+        METRICS.concurrency_target.set(config.initial as i64);
+        ConcurrencyController {
+            target: Mutex::new(config.initial),
+            config,
+            semaphore,
+            parked: Mutex::new(parked),
+            attempts: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn target(&self) -> usize {
+        *self.target.lock().unwrap()
+    }
+
+    // Waits for a free slot, the slot is taken until the permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("Concurrency semaphore is never closed")
+    }
+
+    pub fn record_attempt(&self, failure: Option<FailureCategory>) {
+        let rpc_error = matches!(
+            failure,
+            Some(FailureCategory::RpcRateLimit | FailureCategory::ForkFetchError)
+        );
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.push_back((Instant::now(), rpc_error));
+        while attempts
+            .front()
+            .is_some_and(|(at, _)| at.elapsed() > RPC_ERROR_WINDOW)
+        {
+            attempts.pop_front();
+        }
+    }
+
+    fn rpc_error_rate(&self) -> Option<f64> {
+        let attempts = self.attempts.lock().unwrap();
+        let recent: Vec<bool> = attempts
+            .iter()
+            .filter(|(at, _)| at.elapsed() <= RPC_ERROR_WINDOW)
+            .map(|(_, rpc_error)| *rpc_error)
+            .collect();
+        if recent.len() < RPC_ERROR_MIN_SAMPLES {
+            return None;
+        }
+        Some(recent.iter().filter(|e| **e).count() as f64 / recent.len() as f64)
+    }
+
+    fn decide(
+        &self,
+        pressure: Option<&MemoryPressure>,
+        thresholds: &MemoryPressureThresholds,
+        load_per_cpu: f64,
+        rpc_error_rate: Option<f64>,
+    ) -> Adjustment {
+        let under_pressure = pressure.is_some_and(|p| p.is_under_pressure(thresholds));
+        let overloaded = load_per_cpu > self.config.max_load_per_cpu;
+        let rpc_failing = rpc_error_rate.is_some_and(|rate| rate > self.config.max_rpc_error_rate);
+        if under_pressure || overloaded || rpc_failing {
+            return Adjustment::Decrease;
+        }
+
+        let needed_bytes =
+            ((thresholds.min_available_gb + self.config.memory_per_worker_gb) * GB as f64) as u64;
+        // Unknown available memory is not a reason to stop growing, the pressure check still applies
+        let memory_headroom = pressure
+            .and_then(|p| p.available_bytes)
+            .is_none_or(|available| available >= needed_bytes);
+        let cpu_headroom = load_per_cpu < self.config.max_load_per_cpu * 0.8;
+        if memory_headroom && cpu_headroom {
+            Adjustment::Increase
+        } else {
+            Adjustment::Hold
+        }
+    }
+
+    // Moves parked permits to or from the semaphore until the target is reached. Permits of
+    // running tests can't be taken back, so lowering the target may take a few rounds.
+    fn apply_target(&self) {
+        let target = self.target();
+        let mut parked = self.parked.lock().unwrap();
+        while self.config.max - parked.len() > target {
+            match Arc::clone(&self.semaphore).try_acquire_owned() {
+                Ok(permit) => parked.push(permit),
+                Err(_) => break,
+            }
+        }
+        while self.config.max - parked.len() < target {
+            parked.pop();
+        }
+    }
+
+    fn adjust(&self, adjustment: Adjustment) {
+        let mut target = self.target.lock().unwrap();
+        let previous = *target;
+        *target = match adjustment {
+            Adjustment::Increase => (previous + 1).min(self.config.max),
+            // Back off faster than we grow
+            Adjustment::Decrease => previous
+                .saturating_sub((previous / 4).max(1))
+                .max(self.config.min),
+            Adjustment::Hold => previous,
+        };
+        METRICS.concurrency_target.set(*target as i64);
+        if *target != previous {
+            info!(
+                from = previous,
+                to = *target,
+                ?adjustment,
+                "Concurrency changed"
+            );
+        }
+    }
+
+    // Re-evaluates the target every `adjust_interval_secs`, runs until aborted
+    pub async fn run(
+        &self,
+        memory_pressure: &dyn MemoryPressureSource,
+        thresholds: &MemoryPressureThresholds,
+    ) {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        loop {
+            tokio::time::sleep(Duration::from_secs(self.config.adjust_interval_secs)).await;
+
+            let pressure = match memory_pressure.sample().await {
+                Ok(pressure) => Some(pressure),
+                Err(e) => {
+                    warn!("Error reading memory pressure: {}", e);
+                    None
+                }
+            };
+            let load_per_cpu = System::load_average().one / cpus as f64;
+
+            let adjustment = self.decide(
+                pressure.as_ref(),
+                thresholds,
+                load_per_cpu,
+                self.rpc_error_rate(),
+            );
+            self.adjust(adjustment);
+            self.apply_target();
+        }
+    }
+}
+
+// The forge slot of one job. It is given up while the job only waits, so another job can
+// run forge meanwhile. Jobs outside the controller, like `single`, have no permit to give up.
+#[derive(Default)]
+pub struct ForgeSlot<'a> {
+    concurrency: Option<&'a ConcurrencyController>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<'a> ForgeSlot<'a> {
+    pub fn new(concurrency: &'a ConcurrencyController, permit: OwnedSemaphorePermit) -> Self {
+        ForgeSlot {
+            concurrency: Some(concurrency),
+            permit: Some(permit),
+        }
+    }
+
+    // Runs `wait` without the permit and takes a free one again before returning
+    pub async fn release_while<F: Future>(&mut self, wait: F) -> F::Output {
+        if self.permit.take().is_none() {
+            return wait.await;
+        }
+        let output = wait.await;
+        if let Some(concurrency) = self.concurrency {
+            self.permit = Some(concurrency.acquire().await);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_follows_signals() {
+        let controller = ConcurrencyController::new(ConcurrencyConfig {
+            min: 2,
+            max: 8,
+            initial: 4,
+            ..Default::default()
+        });
+        let thresholds = MemoryPressureThresholds::default();
+        let plenty = MemoryPressure {
+            available_bytes: Some(64 * GB),
+            ..Default::default()
+        };
+        let short = MemoryPressure {
+            available_bytes: Some(GB),
+            ..Default::default()
+        };
+        assert_eq!(controller.semaphore.available_permits(), 4);
+
+        let adjustment = controller.decide(Some(&plenty), &thresholds, 0.1, None);
+        assert_eq!(adjustment, Adjustment::Increase);
+        controller.adjust(adjustment);
+        controller.apply_target();
+        assert_eq!(controller.semaphore.available_permits(), 5);
+
+        // A running test keeps its permit, the target is reached once it finishes
+        let running = controller.semaphore.clone().try_acquire_owned().unwrap();
+        for _ in 0..3 {
+            let adjustment = controller.decide(Some(&short), &thresholds, 0.1, None);
+            assert_eq!(adjustment, Adjustment::Decrease);
+            controller.adjust(adjustment);
+        }
+        controller.apply_target();
+        assert_eq!(controller.target(), 2);
+        assert_eq!(controller.semaphore.available_permits(), 1);
+        drop(running);
+        controller.apply_target();
+        assert_eq!(controller.semaphore.available_permits(), 2);
+
+        for _ in 0..RPC_ERROR_MIN_SAMPLES {
+            controller.record_attempt(Some(FailureCategory::RpcRateLimit));
+        }
+        let rate = controller.rpc_error_rate();
+        assert_eq!(rate, Some(1.0));
+        assert_eq!(
+            controller.decide(Some(&plenty), &thresholds, 0.1, rate),
+            Adjustment::Decrease
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slot_released_while_waiting() {
+        let controller = ConcurrencyController::new(ConcurrencyConfig {
+            min: 1,
+            max: 1,
+            initial: 1,
+            ..Default::default()
+        });
+        let mut slot = ForgeSlot::new(&controller, controller.acquire().await);
+        assert_eq!(controller.semaphore.available_permits(), 0);
+
+        let available = slot
+            .release_while(async { controller.semaphore.available_permits() })
+            .await;
+        assert_eq!(available, 1);
+        assert_eq!(controller.semaphore.available_permits(), 0);
+    }
+}
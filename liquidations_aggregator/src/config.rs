@@ -0,0 +1,50 @@
+use {
+    crate::{archive::*, concurrency::*, forge::*, memory::*, retry::*, shutdown::*},
+    serde::Deserialize,
+    std::{error::Error as StdError, fs, path::Path},
+};
+
+// Settings of the aggregator itself, as opposed to the selection of liquidations
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregatorConfig {
+    pub retry: RetryPolicy,
+    pub limits: ForgeLimits,
+    pub memory_pressure: MemoryPressureThresholds,
+    pub concurrency: ConcurrencyConfig,
+    pub shutdown: ShutdownConfig,
+    pub archive: ArchiveConfig,
+}
+
+impl AggregatorConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn StdError>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        let config = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::failures::*, std::time::Duration};
+
+    #[test]
+    fn test_default_config_parses() {
+        let config: AggregatorConfig = toml::from_str(include_str!("../aggregator.toml")).unwrap();
+
+        assert_eq!(
+            config
+                .retry
+                .rule(FailureCategory::RpcRateLimit)
+                .max_attempts,
+            5
+        );
+        // Not a built-in default, only there when the file was read
+        assert_eq!(
+            config.limits.timeout(30_000_000),
+            Some(Duration::from_secs(7200))
+        );
+    }
+}
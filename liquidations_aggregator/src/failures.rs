@@ -0,0 +1,185 @@
+use {
+    crate::{forge::*, log_parsing::*},
+    regex::Regex,
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt,
+        sync::{Arc, LazyLock},
+    },
+};
+
+// How much of the forge output is kept with a failure, and of stdout in memory
+pub const STDOUT_TAIL_LINES: usize = 200;
+const STDERR_TAIL_LINES: usize = 1000;
+
+const SIGKILL: i32 = 9;
+
+// A bare 429 would also match gas amounts and prices in the logs
+static HTTP_429: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(error|status|code)\W{0,3}429\b").unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCategory {
+    RpcRateLimit,
+    ForkFetchError,
+    AssertionFailed, // assertAssumtions or any other assertion in the test
+    OutOfGas,
+    Timeout,
+    OomKill,             // Killed by the kernel
+    MemoryLimitExceeded, // Killed by us, see ForgeLimits
+    Interrupted,         // Killed by us on shutdown, the job is run again by the next run
+    ParseError,
+    Unknown,
+}
+
+impl FailureCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureCategory::RpcRateLimit => "rpc_rate_limit",
+            FailureCategory::ForkFetchError => "fork_fetch_error",
+            FailureCategory::AssertionFailed => "assertion_failed",
+            FailureCategory::OutOfGas => "out_of_gas",
+            FailureCategory::Timeout => "timeout",
+            FailureCategory::OomKill => "oom_kill",
+            FailureCategory::MemoryLimitExceeded => "memory_limit_exceeded",
+            FailureCategory::Interrupted => "interrupted",
+            FailureCategory::ParseError => "parse_error",
+            FailureCategory::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for FailureCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct ForgeFailure {
+    pub category: FailureCategory,
+    pub message: String,
+    pub exit_code: Option<i32>,
+    pub stdout: String, // Only the end of it when the rest is in stdout_file
+    pub stdout_file: Option<Arc<StdoutFile>>,
+    pub stderr: String,
+    pub duration_ms: i64,
+    pub peak_memory_bytes: i64, // Of the forge process tree, sampled every second
+    // Strategies that finished before forge failed
+    pub partial_results: Box<LiquidationTestResults>,
+}
+
+impl ForgeFailure {
+    // For failures without any forge output
+    pub fn new(category: FailureCategory, message: String) -> Self {
+        ForgeFailure {
+            category,
+            message,
+            exit_code: None,
+            stdout: String::new(),
+            stdout_file: None,
+            stderr: String::new(),
+            duration_ms: 0,
+            peak_memory_bytes: 0,
+            partial_results: Box::default(),
+        }
+    }
+
+    pub fn stdout_tail(&self) -> String {
+        tail_lines(&self.stdout, STDOUT_TAIL_LINES)
+    }
+
+    pub fn stderr_tail(&self) -> String {
+        tail_lines(&self.stderr, STDERR_TAIL_LINES)
+    }
+}
+
+impl fmt::Display for ForgeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (exit code {:?}): {}",
+            self.category, self.exit_code, self.message
+        )
+    }
+}
+
+impl std::error::Error for ForgeFailure {}
+
+fn tail_lines(text: &str, count: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(count)..].join("\n")
+}
+
+// Works on the output of a forge run that exited unsuccessfully.
+// The order matters: a rate limited fork also fails to fetch, and both end in a [FAIL].
+pub fn classify_failure(signal: Option<i32>, stdout: &str, stderr: &str) -> FailureCategory {
+    if signal == Some(SIGKILL) {
+        return FailureCategory::OomKill;
+    }
+
+    let output = format!("{}\n{}", stdout, stderr).to_lowercase();
+    let contains_any = |needles: &[&str]| needles.iter().any(|needle| output.contains(needle));
+
+    if HTTP_429.is_match(&output)
+        || contains_any(&["too many requests", "rate limit", "request limit"])
+    {
+        FailureCategory::RpcRateLimit
+    } else if contains_any(&[
+        "failed to get block",
+        "failed to get account",
+        "failed to get storage",
+        "could not instantiate forked environment",
+        "error sending request",
+        "connection reset",
+        "connection refused",
+    ]) {
+        FailureCategory::ForkFetchError
+    } else if contains_any(&["outofgas", "out of gas"]) {
+        FailureCategory::OutOfGas
+    } else if contains_any(&["[fail", "assertion failed"]) {
+        FailureCategory::AssertionFailed
+    } else {
+        FailureCategory::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_failure() {
+        let assumption = "[FAIL: Close factor is not 50%: 500000000000000000 != 400000000000000000] testLiquidations() (gas: 0)";
+        assert_eq!(
+            classify_failure(None, assumption, ""),
+            FailureCategory::AssertionFailed
+        );
+        assert_eq!(
+            classify_failure(
+                None,
+                assumption,
+                "HTTP error 429 with body: Too Many Requests"
+            ),
+            FailureCategory::RpcRateLimit
+        );
+        assert_eq!(
+            classify_failure(
+                None,
+                "",
+                "Error: failed to get block for block number: 39769788"
+            ),
+            FailureCategory::ForkFetchError
+        );
+        assert_eq!(
+            classify_failure(None, "[FAIL: EvmError: OutOfGas]", ""),
+            FailureCategory::OutOfGas
+        );
+        assert_eq!(
+            classify_failure(Some(SIGKILL), "", ""),
+            FailureCategory::OomKill
+        );
+        assert_eq!(classify_failure(None, "", ""), FailureCategory::Unknown);
+    }
+}
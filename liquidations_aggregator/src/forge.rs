@@ -0,0 +1,434 @@
+use {
+    crate::{
+        failures::*, log_parsing::*, memory::*, metrics::*, selection::*, shutdown::*, simulator::*,
+    },
+    async_trait::async_trait,
+    serde::Deserialize,
+    std::{
+        collections::{HashMap, VecDeque},
+        env,
+        error::Error as StdError,
+        fs,
+        path::{Path, PathBuf},
+        process::Stdio,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    },
+    sysinfo::{Pid, System},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+        process::Command,
+    },
+    tracing::{info, warn, Instrument},
+};
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForgeLimits {
+    // Resident memory of the whole forge process tree, 0 turns the limit off
+    pub memory_limit_gb: f64,
+    // Wall-clock time of one forge run, 0 turns the timeout off
+    pub timeout_secs: u64,
+    // Overrides timeout_secs for the liquidations of an era
+    pub era_timeout_secs: HashMap<Era, u64>,
+}
+
+impl Default for ForgeLimits {
+    fn default() -> Self {
+        ForgeLimits {
+            memory_limit_gb: 10.0,
+            timeout_secs: 3600,
+            era_timeout_secs: HashMap::new(),
+        }
+    }
+}
+
+impl ForgeLimits {
+    pub fn timeout(&self, block_number: i64) -> Option<Duration> {
+        let secs = self
+            .era_timeout_secs
+            .get(&Era::of_block(block_number))
+            .copied()
+            .unwrap_or(self.timeout_secs);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+}
+
+// Everything a successful forge run printed or wrote
+#[derive(Debug, Clone, Default)]
+pub struct ForgeOutput {
+    // Only the last STDOUT_TAIL_LINES lines when the rest is in stdout_file
+    pub stdout: String,
+    pub stdout_file: Option<Arc<StdoutFile>>,
+    pub stderr: String,
+    // The results file, see parse_results_json. test/Foo.t.sol does not write one yet,
+    // forge runs only log their results to the console.
+    pub results_json: Option<String>,
+    // The console output parsed while forge ran, None for output that was not streamed
+    pub parsed: Option<Result<LiquidationTestResults, ParseError>>,
+}
+
+// The complete stdout of a forge run, deleted with the last output or failure referring to it
+#[derive(Debug)]
+pub struct StdoutFile {
+    path: PathBuf,
+}
+
+impl StdoutFile {
+    fn new(transaction_hash: &str) -> Self {
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "forge-stdout-{}-{}-{}.log",
+            transaction_hash,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        StdoutFile {
+            path: env::temp_dir().join(name),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StdoutFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Parses stdout while forge prints it, finished strategies are known before forge exits.
+// All of it goes to a file, only its last lines are kept in memory.
+async fn read_stdout<R: AsyncRead + Unpin>(
+    pipe: Option<R>,
+    file: StdoutFile,
+) -> (String, Option<StdoutFile>, LogParser) {
+    let mut tail = VecDeque::with_capacity(STDOUT_TAIL_LINES);
+    let mut parser = LogParser::default();
+    let Some(pipe) = pipe else {
+        return (String::new(), None, parser);
+    };
+
+    let mut writer = match tokio::fs::File::create(file.path()).await {
+        Ok(created) => Some((file, BufWriter::new(created))),
+        Err(e) => {
+            warn!(
+                "Error creating {}, only the end of stdout is kept: {}",
+                file.path().display(),
+                e
+            );
+            None
+        }
+    };
+    let mut reader = BufReader::new(pipe);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if let Some((_, out)) = &mut writer {
+            if let Err(e) = out.write_all(&buf).await {
+                warn!(
+                    "Error writing stdout to a file, only the end of it is kept: {}",
+                    e
+                );
+                writer = None;
+            }
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\n', '\r']);
+        if tail.len() == STDOUT_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line.to_string());
+
+        let failed_before = parser.error().is_some();
+        let finished = parser.push_line(line).map(str::to_string);
+        if let Some(strategy) = finished {
+            info!(
+                strategy,
+                finished = parser.results().strategies.len(),
+                "Strategy finished"
+            );
+        }
+        if let (false, Some(e)) = (failed_before, parser.error()) {
+            warn!("Error parsing forge output: {}", e);
+        }
+    }
+
+    let file = match writer {
+        Some((file, mut out)) => match out.flush().await {
+            Ok(()) => Some(file),
+            Err(e) => {
+                warn!(
+                    "Error writing stdout to a file, only the end of it is kept: {}",
+                    e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    (Vec::from(tail).join("\n"), file, parser)
+}
+
+async fn read_to_string<R: AsyncRead + Unpin>(pipe: Option<R>) -> String {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        let _ = pipe.read_to_end(&mut buf).await;
+    }
+    String::from_utf8_lossy(&buf).to_string()
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &std::process::ExitStatus) -> Option<i32> {
+    None
+}
+
+// Runs `forge test` of the Foundry project the aggregator lives in
+pub struct ForgeSimulator {
+    limits: ForgeLimits,
+    project_dir: PathBuf,
+}
+
+impl ForgeSimulator {
+    pub fn new(limits: ForgeLimits) -> Result<Self, Box<dyn StdError>> {
+        // Get the current directory and move one level up
+        let current_dir = env::current_dir()?;
+        let project_dir = current_dir
+            .parent()
+            .ok_or("Failed to get parent directory")?
+            .to_path_buf();
+        Ok(ForgeSimulator {
+            limits,
+            project_dir,
+        })
+    }
+}
+
+#[async_trait]
+impl Simulator for ForgeSimulator {
+    fn name(&self) -> &'static str {
+        "forge"
+    }
+
+    async fn simulate(
+        &self,
+        request: &SimulationRequest,
+        shutdown: &Shutdown,
+    ) -> SimulationOutcome {
+        run_forge_test(request, &self.limits, &self.project_dir, shutdown).await
+    }
+}
+
+async fn run_forge_test(
+    request: &SimulationRequest,
+    limits: &ForgeLimits,
+    project_dir: &Path,
+    shutdown: &Shutdown,
+) -> SimulationOutcome {
+    info!("Running forge test");
+
+    // Execute the command in the project directory and wait for it to complete
+    let mut command = Command::new("forge");
+    command
+        .arg("test")
+        .arg("--no-rpc-rate-limit")
+        .arg("--match-test")
+        .arg("testLiquidations")
+        .arg("-vv")
+        .env("TX_HASH", &request.transaction_hash)
+        .env("REPAY_V_TOKEN", &request.repay_v_token)
+        .env("BORROWER", &request.borrower)
+        .env("REPAY_AMOUNT", request.repay_amount.to_string())
+        .env("COLLATERAL_V_TOKEN", &request.collateral_v_token)
+        .env("EXPECTED_SEIZE", request.expected_seize.to_string())
+        .env("GAS_PRICE", request.gas_price.to_string())
+        .current_dir(project_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Its own process group keeps Ctrl-C in the terminal from reaching forge,
+    // running tests get the shutdown grace period instead
+    #[cfg(unix)]
+    command.process_group(0);
+    let mut cmd = command.spawn().map_err(|e| {
+        ForgeFailure::new(
+            FailureCategory::Unknown,
+            format!("Failed to spawn forge: {}", e),
+        )
+    })?;
+    let started = Instant::now();
+
+    // Drain the pipes while the child runs, a full pipe would block forge forever
+    let stdout_file = StdoutFile::new(&request.transaction_hash);
+    let stdout_reader = tokio::spawn(read_stdout(cmd.stdout.take(), stdout_file).in_current_span());
+    let stderr_reader = tokio::spawn(read_to_string(cmd.stderr.take()));
+
+    // Monitor memory usage of forge and everything it spawned
+    let pid = Pid::from_u32(cmd.id().expect("Failed to get child PID"));
+    let memory_limit_bytes = (limits.memory_limit_gb * GB as f64) as u64;
+    let mut sys = System::new();
+    let mut peak_memory_bytes = 0;
+    let timeout = limits.timeout(request.block_number);
+    let mut memory_limit_exceeded = false;
+    let mut timed_out = false;
+    let mut interrupted = false;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            _ = shutdown.killing() => interrupted = true,
+        }
+
+        if !matches!(cmd.try_wait(), Ok(None)) {
+            break; // process exited
+        }
+
+        refresh_process_memory(&mut sys);
+        if interrupted {
+            warn!(%pid, "Killing forge process tree on shutdown");
+            kill_process_tree(&sys, pid);
+            break;
+        }
+
+        if timeout.is_some_and(|timeout| started.elapsed() > timeout) {
+            warn!(%pid, elapsed = ?started.elapsed(), "Killing forge process tree after the timeout");
+            kill_process_tree(&sys, pid);
+            timed_out = true;
+            break;
+        }
+
+        let memory_bytes = process_tree_memory(&sys, pid);
+        peak_memory_bytes = peak_memory_bytes.max(memory_bytes);
+        if memory_limit_bytes > 0 && memory_bytes > memory_limit_bytes {
+            warn!(
+                %pid,
+                memory_gb = memory_bytes as f64 / GB as f64,
+                limit_gb = limits.memory_limit_gb,
+                "Killing forge process tree over the memory limit"
+            );
+            kill_process_tree(&sys, pid);
+            memory_limit_exceeded = true;
+            break;
+        }
+    }
+
+    let status = cmd.wait().await;
+    let (stdout, stdout_file, parser) = stdout_reader.await.unwrap_or_default();
+    let stdout_file = stdout_file.map(Arc::new);
+    let stderr = stderr_reader.await.unwrap_or_default();
+    let duration = started.elapsed();
+
+    let observe = |outcome: &str| {
+        METRICS
+            .forge_run_duration_seconds
+            .with_label_values(&[outcome])
+            .observe(duration.as_secs_f64());
+        METRICS
+            .forge_peak_memory_bytes
+            .observe(peak_memory_bytes as f64);
+    };
+
+    let (category, message, exit_code) = match status {
+        _ if interrupted => (
+            FailureCategory::Interrupted,
+            "Killed on shutdown".to_string(),
+            None,
+        ),
+        _ if timed_out => (
+            FailureCategory::Timeout,
+            format!("Timed out after {:.0?}", duration),
+            None,
+        ),
+        _ if memory_limit_exceeded => (
+            FailureCategory::MemoryLimitExceeded,
+            format!(
+                "Forge process tree used more than {} GB",
+                limits.memory_limit_gb
+            ),
+            None,
+        ),
+        Ok(status) if status.success() => {
+            observe("success");
+            return Ok(ForgeOutput {
+                stdout,
+                stdout_file,
+                stderr,
+                results_json: None,
+                parsed: Some(parser.finish()),
+            });
+        }
+        // Forge ends with a summary of the failing tests, the end of stdout is enough
+        Ok(status) => (
+            classify_failure(exit_signal(&status), &stdout, &stderr),
+            format!("Command failed with status: {}", status),
+            status.code(),
+        ),
+        Err(e) => (
+            FailureCategory::Unknown,
+            format!("Failed to wait for forge: {}", e),
+            None,
+        ),
+    };
+    observe(category.as_str());
+
+    Err(ForgeFailure {
+        category,
+        message,
+        exit_code,
+        stdout,
+        stdout_file,
+        stderr,
+        duration_ms: duration.as_millis() as i64,
+        peak_memory_bytes: peak_memory_bytes as i64,
+        partial_results: Box::new(parser.results().clone()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_per_era() {
+        let limits: ForgeLimits = toml::from_str(
+            "timeout_secs = 600\nera_timeout_secs = { istanbul = 0, cancun = 1200 }",
+        )
+        .unwrap();
+
+        assert_eq!(limits.timeout(1), None);
+        assert_eq!(limits.timeout(35490444), Some(Duration::from_secs(600)));
+        assert_eq!(limits.timeout(39769787), Some(Duration::from_secs(1200)));
+    }
+
+    #[tokio::test]
+    async fn test_read_stdout_keeps_the_tail_in_memory() {
+        let stdout: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        let file = StdoutFile::new("0xtest");
+        let path = file.path().to_path_buf();
+
+        let (tail, file, parser) = read_stdout(Some(stdout.as_bytes()), file).await;
+        assert_eq!(tail.lines().count(), STDOUT_TAIL_LINES);
+        assert_eq!(tail.lines().next(), Some("line 300"));
+        assert!(tail.ends_with("line 499"));
+        assert!(parser.error().is_none());
+        assert_eq!(fs::read_to_string(&path).unwrap(), stdout);
+
+        // The file goes away with the output that refers to it
+        drop(file);
+        assert!(!path.exists());
+    }
+}
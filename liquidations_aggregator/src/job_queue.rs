@@ -0,0 +1,325 @@
+use {
+    crate::{db_client::*, failures::*, log_parsing::*},
+    async_trait::async_trait,
+    deadpool_postgres::Pool,
+    std::{collections::HashMap, error::Error as StdError, sync::Arc, time::Duration},
+};
+
+// A leased job has to be renewed before this runs out, otherwise another worker takes it over
+pub const LEASE_DURATION: Duration = Duration::from_secs(10 * 60);
+pub const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+// Leases that keep expiring (crashed worker, killed machine) stop being handed out
+pub const MAX_ATTEMPTS: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Pending,
+    Leased,
+    Succeeded,
+    Failed,
+    Skipped,
+    Interrupted, // Stopped by a shutdown, leased again like a pending job
+}
+
+impl JobState {
+    pub const ALL: [JobState; 6] = [
+        JobState::Pending,
+        JobState::Leased,
+        JobState::Succeeded,
+        JobState::Failed,
+        JobState::Skipped,
+        JobState::Interrupted,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Leased => "leased",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Skipped => "skipped",
+            JobState::Interrupted => "interrupted",
+        }
+    }
+}
+
+// Where the jobs of a worker come from and what came of them goes to
+#[async_trait]
+pub trait JobStore: Send + Sync {
+    async fn lease(&self) -> Result<Option<LiquidationData>, Box<dyn StdError>>;
+    async fn renew(&self, transaction_hashes: &[String]) -> Result<(), Box<dyn StdError>>;
+    async fn finish(
+        &self,
+        transaction_hash: &str,
+        state: JobState,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn StdError>>;
+    async fn record_failure(
+        &self,
+        transaction_hash: &str,
+        attempt: u32,
+        failure: &ForgeFailure,
+    ) -> Result<(), Box<dyn StdError>>;
+    async fn store_results(
+        &self,
+        transaction_hash: &str,
+        parsed_data: &LiquidationTestResults,
+    ) -> Result<(), Box<dyn StdError>>;
+}
+
+// The job queue table, shared by the workers on every host
+pub struct PgJobStore {
+    pool: Arc<Pool>,
+    worker_id: String,
+}
+
+impl PgJobStore {
+    pub fn new(pool: Arc<Pool>, worker_id: &str) -> Self {
+        PgJobStore {
+            pool,
+            worker_id: worker_id.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl JobStore for PgJobStore {
+    async fn lease(&self) -> Result<Option<LiquidationData>, Box<dyn StdError>> {
+        lease_job(self.pool.clone(), &self.worker_id).await
+    }
+
+    async fn renew(&self, transaction_hashes: &[String]) -> Result<(), Box<dyn StdError>> {
+        renew_leases(self.pool.clone(), &self.worker_id, transaction_hashes).await
+    }
+
+    async fn finish(
+        &self,
+        transaction_hash: &str,
+        state: JobState,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn StdError>> {
+        finish_job(
+            self.pool.clone(),
+            &self.worker_id,
+            transaction_hash,
+            state,
+            error,
+        )
+        .await
+    }
+
+    async fn record_failure(
+        &self,
+        transaction_hash: &str,
+        attempt: u32,
+        failure: &ForgeFailure,
+    ) -> Result<(), Box<dyn StdError>> {
+        insert_failure(self.pool.clone(), transaction_hash, attempt, failure).await
+    }
+
+    async fn store_results(
+        &self,
+        transaction_hash: &str,
+        parsed_data: &LiquidationTestResults,
+    ) -> Result<(), Box<dyn StdError>> {
+        insert_with_retries(self.pool.clone(), transaction_hash, parsed_data).await
+    }
+}
+
+// Identifies this process in `leased_by`
+pub fn worker_id() -> String {
+    let host = sysinfo::System::host_name().unwrap_or_else(|| "unknown".to_string());
+    format!("{}:{}", host, std::process::id())
+}
+
+pub(crate) async fn ensure_job_queue(pool: Arc<Pool>) -> Result<(), Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS liquidation_test_jobs (
+    transaction_hash TEXT PRIMARY KEY,
+    block_number BIGINT NOT NULL,
+    v_token TEXT NOT NULL,
+    borrower TEXT NOT NULL,
+    repay_amount NUMERIC NOT NULL,
+    v_token_collateral TEXT NOT NULL,
+    seize_tokens NUMERIC NOT NULL,
+    gas_price NUMERIC NOT NULL,
+    state TEXT NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    leased_by TEXT,
+    lease_expires_at TIMESTAMPTZ,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS liquidation_test_jobs_state_idx ON liquidation_test_jobs (state);
+CREATE INDEX IF NOT EXISTS liquidation_test_jobs_lease_idx ON liquidation_test_jobs (block_number, transaction_hash)
+    WHERE state IN ('pending', 'interrupted', 'leased');",
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Adds the liquidations to the queue. Jobs skipped by an earlier run go back to pending,
+// everything else already in the queue is left alone. Returns the number of new or reset jobs.
+pub(crate) async fn enqueue_jobs(
+    pool: Arc<Pool>,
+    liquidation_data: &[LiquidationData],
+) -> Result<u64, Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    let column = |f: fn(&LiquidationData) -> String| -> Vec<String> {
+        liquidation_data.iter().map(f).collect()
+    };
+    let block_numbers: Vec<i64> = liquidation_data.iter().map(|d| d.block_number).collect();
+
+    let inserted = client
+        .execute(
+            "INSERT INTO liquidation_test_jobs (
+    transaction_hash, block_number, v_token, borrower, repay_amount, v_token_collateral, seize_tokens, gas_price
+)
+SELECT * FROM unnest($1::TEXT[], $2::BIGINT[], $3::TEXT[], $4::TEXT[], $5::TEXT[]::NUMERIC[], $6::TEXT[], $7::TEXT[]::NUMERIC[], $8::TEXT[]::NUMERIC[])
+ON CONFLICT (transaction_hash)
+DO UPDATE SET
+    state = 'pending',
+    attempts = 0,
+    leased_by = NULL,
+    lease_expires_at = NULL,
+    updated_at = now()
+WHERE liquidation_test_jobs.state = 'skipped'",
+            &[
+                &column(|d| d.transaction_hash.clone()),
+                &block_numbers,
+                &column(|d| d.v_token.clone()),
+                &column(|d| d.borrower.clone()),
+                &column(|d| d.repay_amount.to_string()),
+                &column(|d| d.v_token_collateral.clone()),
+                &column(|d| d.seize_tokens.to_string()),
+                &column(|d| d.gas_price.to_string()),
+            ],
+        )
+        .await?;
+
+    Ok(inserted)
+}
+
+pub(crate) async fn retry_failed_jobs(pool: Arc<Pool>) -> Result<u64, Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    let reset = client
+        .execute(
+            "UPDATE liquidation_test_jobs
+SET state = 'pending', attempts = 0, leased_by = NULL, lease_expires_at = NULL, updated_at = now()
+WHERE state = 'failed'",
+            &[],
+        )
+        .await?;
+
+    Ok(reset)
+}
+
+// Takes the next pending or interrupted job, or one whose lease expired. SKIP LOCKED keeps
+// concurrent workers on other hosts from getting the same job. Jobs go in block order, which
+// liquidation_test_jobs_lease_idx serves without sorting the queue on every lease.
+pub(crate) async fn lease_job(
+    pool: Arc<Pool>,
+    worker_id: &str,
+) -> Result<Option<LiquidationData>, Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    client
+        .execute(
+            "UPDATE liquidation_test_jobs
+SET state = 'failed', last_error = 'lease expired too many times', leased_by = NULL, updated_at = now()
+WHERE state = 'leased' AND lease_expires_at < now() AND attempts >= $1",
+            &[&MAX_ATTEMPTS],
+        )
+        .await?;
+
+    let row = client
+        .query_opt(
+            "UPDATE liquidation_test_jobs
+SET state = 'leased',
+    leased_by = $1,
+    lease_expires_at = now() + make_interval(secs => $2),
+    attempts = attempts + 1,
+    updated_at = now()
+WHERE transaction_hash = (
+    SELECT transaction_hash
+    FROM liquidation_test_jobs
+    WHERE state IN ('pending', 'interrupted', 'leased')
+        AND (state <> 'leased' OR lease_expires_at < now())
+    ORDER BY block_number, transaction_hash
+    LIMIT 1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING transaction_hash, block_number, v_token, borrower, repay_amount::TEXT, v_token_collateral, seize_tokens::TEXT, gas_price::TEXT",
+            &[&worker_id, &LEASE_DURATION.as_secs_f64()],
+        )
+        .await?;
+
+    Ok(row.as_ref().map(liquidation_data_from_row))
+}
+
+pub(crate) async fn renew_leases(
+    pool: Arc<Pool>,
+    worker_id: &str,
+    transaction_hashes: &[String],
+) -> Result<(), Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    client
+        .execute(
+            "UPDATE liquidation_test_jobs
+SET lease_expires_at = now() + make_interval(secs => $3), updated_at = now()
+WHERE state = 'leased' AND leased_by = $1 AND transaction_hash = ANY($2)",
+            &[
+                &worker_id,
+                &transaction_hashes,
+                &LEASE_DURATION.as_secs_f64(),
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Only the worker holding the lease can finish a job
+pub(crate) async fn finish_job(
+    pool: Arc<Pool>,
+    worker_id: &str,
+    transaction_hash: &str,
+    state: JobState,
+    error: Option<&str>,
+) -> Result<(), Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    client
+        .execute(
+            "UPDATE liquidation_test_jobs
+SET state = $3, last_error = $4, leased_by = NULL, lease_expires_at = NULL, updated_at = now()
+WHERE transaction_hash = $2 AND state = 'leased' AND leased_by = $1",
+            &[&worker_id, &transaction_hash, &state.as_str(), &error],
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn fetch_job_counts(
+    pool: Arc<Pool>,
+) -> Result<HashMap<String, i64>, Box<dyn StdError>> {
+    let client = pool.get().await?;
+
+    let rows = client
+        .query(
+            "SELECT state, count(*) FROM liquidation_test_jobs GROUP BY state",
+            &[],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
@@ -1,23 +1,48 @@
-use {crate::big_num::*, serde::Serialize, std::collections::HashMap};
+use {
+    crate::{big_num::*, validation::*},
+    indexmap::IndexMap,
+    serde::{Deserialize, Serialize},
+    std::fmt,
+};
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StrategyRunReport {
-    pub initial_health_factor: String, // Without any accrue
-    pub final_health_factor: String,
+    pub initial_health_factor: Decimal18, // Without any accrue
+    pub final_health_factor: Decimal18,
     pub liquidations: Vec<LiquidationReport>,
     pub assets: Vec<AssetReport>,
     pub gas_usage: GasUsage,
     pub gas_price: U256,
     pub chain_coin_price: U256,
-    pub gas_fee_usd: String, // gas_usage.total * gas_price * chain_coin_price
-    pub repaid_usd: String,  // sum by asset the sum of repaid * price
-    pub seized_usd: String,
-    pub profit_usd: String,
+    pub gas_fee_usd: Decimal18, // gas_usage.total * gas_price * chain_coin_price
+    pub repaid_usd: Decimal18,  // sum by asset the sum of repaid * price
+    pub seized_usd: Decimal18,
+    pub profit_usd: Decimal18,
+    // Every liquidation the strategy tried, also the ones that did not cover gas
+    #[serde(default)]
+    pub steps: Vec<LiquidationStepEvent>,
 
+    #[serde(default)] // Only the console output has raw lines
     pub raw: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Clone)]
+// A `did a liquidation (repayUsd, seizedUsd, gasInUsd)` line, logged before the report
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LiquidationStepEvent {
+    pub repaid_usd: Decimal18,
+    pub seized_usd: Decimal18,
+    pub gas_fee_usd: Decimal18,
+}
+
+// Protocol parameters the test logs before the first test case
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SimulationHeader {
+    pub incentive: Option<U256>,        // liquidationIncentiveMantissa
+    pub liquidator: Option<String>,     // The Liquidator contract, zero address if none
+    pub treasury_percent: Option<U256>, // Only logged by older tests
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LiquidationReport {
     pub repay_symbol: String,
     pub collateral_symbol: String,
@@ -27,13 +52,13 @@ pub struct LiquidationReport {
     pub collateral_v_token_gained: U256,
     pub collateral_underlying_gained: U256, // Includes redeem fees
     pub gas_used: U256,
-    pub post_health_factor: String,
+    pub post_health_factor: Decimal18,
     // Derivatives
-    pub repaid_usd: String, // repay_amount * price
-    pub seized_usd: String, // collateral_underlying_gained * price
+    pub repaid_usd: Decimal18, // repay_amount * price
+    pub seized_usd: Decimal18, // collateral_underlying_gained * price
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AssetReport {
     pub initial_data: AssetData,
     pub repaid: U256,
@@ -44,7 +69,7 @@ pub struct AssetReport {
     pub gas_used_to_redeem: U256,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AssetData {
     pub symbol: String,
     pub v_token: String,
@@ -55,12 +80,12 @@ pub struct AssetData {
     pub exchange_rate: U256,
     pub price: U256,
     // Derivatives:
-    pub borrow_value_usd: String,     // borrow_amount * price
-    pub collateral_value_usd: String, // min(collateral_amount * exchange_rate, cash) * price
+    pub borrow_value_usd: Decimal18,     // borrow_amount * price
+    pub collateral_value_usd: Decimal18, // min(collateral_amount * exchange_rate, cash) * price
     pub is_collateral_capped_by_cash: bool,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GasUsage {
     pub approves: U256,
     pub liquidations: U256,
@@ -68,33 +93,165 @@ pub struct GasUsage {
     pub total: U256,
 }
 
-#[derive(Debug, Serialize, Clone)]
+// The strategies Foo.t.sol runs, each stored in its own venus_liquidation_tests column.
+// Test cases under other names are kept as well, in the other_strategies column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    Repeat,
+    UpToCloseFactor,
+    Drain,
+    LargeBorrow,
+    DrainSameToken,
+    LargestCfFirst,
+    SmallestCfFirst,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 7] = [
+        Strategy::Repeat,
+        Strategy::UpToCloseFactor,
+        Strategy::Drain,
+        Strategy::LargeBorrow,
+        Strategy::DrainSameToken,
+        Strategy::LargestCfFirst,
+        Strategy::SmallestCfFirst,
+    ];
+
+    // Name of the forge test case, as in its `Tests case:` marker
+    pub fn test_name(self) -> &'static str {
+        match self {
+            Strategy::Repeat => "repeatLiquidation",
+            Strategy::UpToCloseFactor => "upToCloseFactorLiquidation",
+            Strategy::Drain => "drainLiquidation",
+            Strategy::LargeBorrow => "largestBorrow",
+            Strategy::DrainSameToken => "drainSameToken",
+            Strategy::LargestCfFirst => "largestCollateralFactorFirst",
+            Strategy::SmallestCfFirst => "smallestCollateralFactorFirst",
+        }
+    }
+
+    pub fn column(self) -> &'static str {
+        match self {
+            Strategy::Repeat => "repeat",
+            Strategy::UpToCloseFactor => "up_to_close_factor",
+            Strategy::Drain => "drain",
+            Strategy::LargeBorrow => "large_borrow",
+            Strategy::DrainSameToken => "drain_same_token",
+            Strategy::LargestCfFirst => "largest_cf_first",
+            Strategy::SmallestCfFirst => "smallest_cf_first",
+        }
+    }
+
+    pub fn from_test_name(test_name: &str) -> Option<Strategy> {
+        Strategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.test_name() == test_name)
+    }
+}
+
+// Reports by test case name, in the order the test ran them
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
 pub struct LiquidationTestResults {
-    pub repeat: StrategyRunReport,
-    pub up_to_close_factor: StrategyRunReport,
-    pub drain: StrategyRunReport,
-    pub large_borrow: StrategyRunReport,
-    pub drain_same_token: StrategyRunReport,
-    pub largest_cf_first: StrategyRunReport,
-    pub smallest_cf_first: StrategyRunReport,
+    // Not part of the results file, it is stored in its own column
+    #[serde(skip)]
+    pub header: SimulationHeader,
+    // Set by validate after parsing, also stored in its own column
+    #[serde(skip)]
+    pub violations: Vec<Violation>,
+    pub strategies: IndexMap<String, StrategyRunReport>,
+}
+
+impl LiquidationTestResults {
+    pub fn strategy(&self, strategy: Strategy) -> Option<&StrategyRunReport> {
+        self.strategies.get(strategy.test_name())
+    }
+
+    // Known strategies the test did not report
+    pub fn missing(&self) -> Vec<Strategy> {
+        Strategy::ALL
+            .into_iter()
+            .filter(|strategy| self.strategy(*strategy).is_none())
+            .collect()
+    }
+
+    // Reported test cases that are not a known strategy
+    pub fn unknown(&self) -> IndexMap<&str, &StrategyRunReport> {
+        self.strategies
+            .iter()
+            .filter(|(test_name, _)| Strategy::from_test_name(test_name).is_none())
+            .map(|(test_name, report)| (test_name.as_str(), report))
+            .collect()
+    }
 }
 
-fn extract_str(line: &str) -> String {
-    line.split_whitespace().last().unwrap().to_owned()
+// Where and why the console output of a strategy could not be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub strategy: Option<String>,
+    pub section: Option<&'static str>, // header, step, asset, liquidation, gasUsage or strategyRunReport
+    pub line_number: Option<usize>,    // 1-based, in the whole forge output
+    pub line: Option<String>,
+    pub message: String,
 }
 
-fn extract_u256(line: &str) -> U256 {
-    let dec_str = line.split_whitespace().last().unwrap();
-    match U256::from_dec_str(dec_str) {
-        Ok(value) => value,
-        Err(_) => panic!("kek"),
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(strategy) = &self.strategy {
+            write!(f, " in {}", strategy)?;
+        }
+        if let Some(section) = self.section {
+            write!(f, " {} section", section)?;
+        }
+        if let (Some(line_number), Some(line)) = (self.line_number, &self.line) {
+            write!(f, " at line {}: `{}`", line_number, line)?;
+        }
+        Ok(())
     }
 }
 
-pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
+impl std::error::Error for ParseError {}
+
+fn extract_str(line: &str) -> Result<String, String> {
+    match line.split_whitespace().last() {
+        Some(value) => Ok(value.to_owned()),
+        None => Err("Missing value".to_string()),
+    }
+}
+
+fn extract_u256(line: &str) -> Result<U256, String> {
+    let dec_str = extract_str(line)?;
+    U256::from_dec_str(&dec_str).map_err(|_| format!("`{}` is not a decimal number", dec_str))
+}
+
+fn extract_decimal(line: &str) -> Result<Decimal18, String> {
+    extract_str(line)?.parse()
+}
+
+const STEP_PREFIX: &str = "did a liquidation (repayUsd, seizedUsd, gasInUsd)";
+
+fn parse_step(values: &str) -> Result<LiquidationStepEvent, String> {
+    let values: Vec<&str> = values.split_whitespace().collect();
+    let [repaid_usd, seized_usd, gas_fee_usd] = values[..] else {
+        return Err(format!("Expected 3 values, got {}", values.len()));
+    };
+    Ok(LiquidationStepEvent {
+        repaid_usd: repaid_usd.parse()?,
+        seized_usd: seized_usd.parse()?,
+        gas_fee_usd: gas_fee_usd.parse()?,
+    })
+}
+
+// `first_line` is the line number of the first of the lines in the forge output
+pub fn parse_strategy_run_report(
+    strategy: &str,
+    first_line: usize,
+    lines: Vec<String>,
+) -> Result<StrategyRunReport, ParseError> {
     let mut current_report = StrategyRunReport {
-        initial_health_factor: "".to_string(),
-        final_health_factor: "".to_string(),
+        initial_health_factor: Decimal18::default(),
+        final_health_factor: Decimal18::default(),
         liquidations: Vec::new(),
         assets: Vec::new(),
         gas_usage: GasUsage {
@@ -105,10 +262,11 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
         },
         gas_price: U256::zero(),
         chain_coin_price: U256::zero(),
-        gas_fee_usd: "".to_string(),
-        repaid_usd: "".to_string(),
-        seized_usd: "".to_string(),
-        profit_usd: "".to_string(),
+        gas_fee_usd: Decimal18::default(),
+        repaid_usd: Decimal18::default(),
+        seized_usd: Decimal18::default(),
+        profit_usd: Decimal18::default(),
+        steps: Vec::new(),
         raw: lines.clone(),
     };
 
@@ -118,7 +276,7 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
 
     let mut parsing_strategy = false;
 
-    for line in lines.iter() {
+    for (i, line) in lines.iter().enumerate() {
         if line.starts_with("strategyRunReport start") {
             parsing_strategy = true;
         } else if line.starts_with("strategyRunReport end") {
@@ -126,9 +284,37 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
         }
 
         if !parsing_strategy {
+            // Steps are logged while the strategy runs, before its report
+            if let Some(values) = line.strip_prefix(STEP_PREFIX) {
+                let step = parse_step(values).map_err(|message| ParseError {
+                    strategy: Some(strategy.to_string()),
+                    section: Some("step"),
+                    line_number: Some(first_line + i),
+                    line: Some(line.clone()),
+                    message,
+                })?;
+                current_report.steps.push(step);
+            }
             continue;
         }
 
+        let section = if current_asset.is_some() {
+            "asset"
+        } else if current_liquidation.is_some() {
+            "liquidation"
+        } else if current_gas_usage.is_some() {
+            "gasUsage"
+        } else {
+            "strategyRunReport"
+        };
+        let fail = |message: String| ParseError {
+            strategy: Some(strategy.to_string()),
+            section: Some(section),
+            line_number: Some(first_line + i),
+            line: Some(line.clone()),
+            message,
+        };
+
         if line.starts_with("asset start") {
             current_asset = Some(AssetReport {
                 initial_data: AssetData {
@@ -140,8 +326,8 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
                     cash: U256::zero(),
                     exchange_rate: U256::zero(),
                     price: U256::zero(),
-                    borrow_value_usd: "".to_string(),
-                    collateral_value_usd: "".to_string(),
+                    borrow_value_usd: Decimal18::default(),
+                    collateral_value_usd: Decimal18::default(),
                     is_collateral_capped_by_cash: false,
                 },
                 repaid: U256::zero(),
@@ -158,39 +344,40 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
             current_asset = None;
         } else if let Some(ref mut asset) = current_asset {
             if line.starts_with("symbol ") {
-                asset.initial_data.symbol = extract_str(line);
+                asset.initial_data.symbol = extract_str(line).map_err(&fail)?;
             } else if line.starts_with("vtoken ") {
-                asset.initial_data.v_token = extract_str(line);
+                asset.initial_data.v_token = extract_str(line).map_err(&fail)?;
             } else if line.starts_with("collateralFactor ") {
-                asset.initial_data.collateral_factor = extract_u256(line);
+                asset.initial_data.collateral_factor = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("collateralAmount ") {
-                asset.initial_data.collateral_amount = extract_u256(line);
+                asset.initial_data.collateral_amount = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("borrowAmount ") {
-                asset.initial_data.borrow_amount = extract_u256(line);
+                asset.initial_data.borrow_amount = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("cash ") {
-                asset.initial_data.cash = extract_u256(line);
+                asset.initial_data.cash = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("exchangeRate ") {
-                asset.initial_data.exchange_rate = extract_u256(line);
+                asset.initial_data.exchange_rate = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("price ") {
-                asset.initial_data.price = extract_u256(line);
+                asset.initial_data.price = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("borrowValueUsd ") {
-                asset.initial_data.borrow_value_usd = extract_str(line);
+                asset.initial_data.borrow_value_usd = extract_decimal(line).map_err(&fail)?;
             } else if line.starts_with("collateralValueUsd ") {
-                asset.initial_data.collateral_value_usd = extract_str(line);
+                asset.initial_data.collateral_value_usd = extract_decimal(line).map_err(&fail)?;
             } else if line.starts_with("isCollateralCappedByCash ") {
-                asset.initial_data.is_collateral_capped_by_cash = extract_str(line) == "true";
+                asset.initial_data.is_collateral_capped_by_cash =
+                    extract_str(line).map_err(&fail)? == "true";
             } else if line.starts_with("repaid ") {
-                asset.repaid = extract_u256(line);
+                asset.repaid = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("collateralVTokenGained ") {
-                asset.collateral_v_token_gained = extract_u256(line);
+                asset.collateral_v_token_gained = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("collateralUnderlyingGained ") {
-                asset.collateral_underlying_gained = extract_u256(line);
+                asset.collateral_underlying_gained = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("liquidationsParticipated ") {
-                asset.liquidations_participated = extract_u256(line);
+                asset.liquidations_participated = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("gasUsedToApprove ") {
-                asset.gas_used_to_approve = extract_u256(line);
+                asset.gas_used_to_approve = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("gasUsedToRedeem ") {
-                asset.gas_used_to_redeem = extract_u256(line);
+                asset.gas_used_to_redeem = extract_u256(line).map_err(&fail)?;
             }
         }
 
@@ -205,9 +392,9 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
                 collateral_v_token_gained: U256::zero(),
                 collateral_underlying_gained: U256::zero(),
                 gas_used: U256::zero(),
-                post_health_factor: "".to_string(),
-                repaid_usd: "".to_string(),
-                seized_usd: "".to_string(),
+                post_health_factor: Decimal18::default(),
+                repaid_usd: Decimal18::default(),
+                seized_usd: Decimal18::default(),
             });
         } else if line.starts_with("liquidation end") {
             if let Some(ref liquidation) = current_liquidation {
@@ -216,27 +403,27 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
             current_liquidation = None;
         } else if let Some(ref mut liquidation) = current_liquidation {
             if line.starts_with("repaySymbol ") {
-                liquidation.repay_symbol = extract_str(line);
+                liquidation.repay_symbol = extract_str(line).map_err(&fail)?;
             } else if line.starts_with("collateralSymbol ") {
-                liquidation.collateral_symbol = extract_str(line);
+                liquidation.collateral_symbol = extract_str(line).map_err(&fail)?;
             } else if line.starts_with("repayVToken ") {
-                liquidation.repay_v_token = extract_str(line);
+                liquidation.repay_v_token = extract_str(line).map_err(&fail)?;
             } else if line.starts_with("collateralVToken ") {
-                liquidation.collateral_v_token = extract_str(line);
+                liquidation.collateral_v_token = extract_str(line).map_err(&fail)?;
             } else if line.starts_with("repayAmount ") {
-                liquidation.repay_amount = extract_u256(line);
+                liquidation.repay_amount = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("collateralVTokenGained ") {
-                liquidation.collateral_v_token_gained = extract_u256(line);
+                liquidation.collateral_v_token_gained = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("collateralUnderlyingGained ") {
-                liquidation.collateral_underlying_gained = extract_u256(line);
+                liquidation.collateral_underlying_gained = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("gasUsed ") {
-                liquidation.gas_used = extract_u256(line);
+                liquidation.gas_used = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("postHealthFactor ") {
-                liquidation.post_health_factor = extract_str(line);
+                liquidation.post_health_factor = extract_decimal(line).map_err(&fail)?;
             } else if line.starts_with("repaidUsd ") {
-                liquidation.repaid_usd = extract_str(line);
+                liquidation.repaid_usd = extract_decimal(line).map_err(&fail)?;
             } else if line.starts_with("seizedUsd ") {
-                liquidation.seized_usd = extract_str(line);
+                liquidation.seized_usd = extract_decimal(line).map_err(&fail)?;
             }
         }
 
@@ -255,105 +442,158 @@ pub fn parse_strategy_run_report(lines: Vec<String>) -> StrategyRunReport {
             current_gas_usage = None;
         } else if let Some(ref mut gas_usage) = current_gas_usage {
             if line.starts_with("approves ") {
-                gas_usage.approves = extract_u256(line);
+                gas_usage.approves = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("liquidations ") {
-                gas_usage.liquidations = extract_u256(line);
+                gas_usage.liquidations = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("redeems ") {
-                gas_usage.redeems = extract_u256(line);
+                gas_usage.redeems = extract_u256(line).map_err(&fail)?;
             } else if line.starts_with("total ") {
-                gas_usage.total = extract_u256(line);
+                gas_usage.total = extract_u256(line).map_err(&fail)?;
             }
         }
 
         // Handle strategy data parsing
         if line.starts_with("initialHealthFactor ") {
-            current_report.initial_health_factor = extract_str(line);
+            current_report.initial_health_factor = extract_decimal(line).map_err(&fail)?;
         } else if line.starts_with("finalHealthFactor ") {
-            current_report.final_health_factor = extract_str(line);
+            current_report.final_health_factor = extract_decimal(line).map_err(&fail)?;
         } else if line.starts_with("gasPrice ") {
-            current_report.gas_price = extract_u256(line);
+            current_report.gas_price = extract_u256(line).map_err(&fail)?;
         } else if line.starts_with("chainCoinPrice ") {
-            current_report.chain_coin_price = extract_u256(line);
+            current_report.chain_coin_price = extract_u256(line).map_err(&fail)?;
         } else if line.starts_with("gasFeeUsd ") {
-            current_report.gas_fee_usd = extract_str(line);
+            current_report.gas_fee_usd = extract_decimal(line).map_err(&fail)?;
         } else if line.starts_with("repaidUsd ") {
-            current_report.repaid_usd = extract_str(line);
+            current_report.repaid_usd = extract_decimal(line).map_err(&fail)?;
         } else if line.starts_with("seizedUsd ") {
-            current_report.seized_usd = extract_str(line);
+            current_report.seized_usd = extract_decimal(line).map_err(&fail)?;
         } else if line.starts_with("profitUsd ") {
-            current_report.profit_usd = extract_str(line);
+            current_report.profit_usd = extract_decimal(line).map_err(&fail)?;
         }
     }
 
-    current_report
+    Ok(current_report)
 }
 
-// Main log parser function
-pub fn parse_logs(logs: &str) -> LiquidationTestResults {
-    let mut test_case_map: HashMap<String, Vec<String>> = HashMap::new();
+// A JSON object with one key per test case, named like its `Tests case:` marker
+// (`repeatLiquidation`, ...) in the order the cases ran. Each value is a StrategyRunReport
+// with snake_case fields, U256 as decimal strings and USD values and health factors as
+// decimal strings with 18 decimals, `steps` and `raw` may be left out. The simulation header
+// is only logged to the console.
+//
+//   {"repeatLiquidation": {"initial_health_factor": "0.888549544472118463", ...
+//     "gas_price": "5000000000", "profit_usd": "93.895365798778689364"}}
+pub fn parse_results_json(json: &str) -> Result<LiquidationTestResults, serde_json::Error> {
+    serde_json::from_str(json)
+}
 
-    let lines = logs.lines();
-    let mut current_test: Option<String> = None;
-    let mut current_raw_lines: Vec<String> = Vec::new();
+// Parses forge output line by line as it is printed. Each strategy report is complete
+// at its `Tests case end`, lines after the first error are ignored.
+#[derive(Debug, Default)]
+pub struct LogParser {
+    line_number: usize,
+    current_test: Option<String>,
+    current_first_line: usize,
+    current_raw_lines: Vec<String>,
+    results: LiquidationTestResults,
+    error: Option<ParseError>,
+}
 
-    for line in lines {
+impl LogParser {
+    // Returns the name of the strategy the line completed
+    pub fn push_line(&mut self, line: &str) -> Option<&str> {
+        self.line_number += 1;
+        if self.error.is_some() {
+            return None;
+        }
         let line = line.trim_start(); // Remove leading whitespace
 
-        if line.starts_with("Tests case: ") {
-            // Extract the test case name
-            if let Some(test_name) = line.strip_prefix("Tests case: ") {
-                current_test = Some(test_name.to_string());
-                current_raw_lines.clear();
-            }
+        if let Some(test_name) = line.strip_prefix("Tests case: ") {
+            self.current_test = Some(test_name.to_string());
+            self.current_first_line = self.line_number + 1;
+            self.current_raw_lines.clear();
         } else if line.starts_with("Tests case end") {
             // When "Tests case end" is found, process the current test case
-            if let Some(test_name) = &current_test {
-                test_case_map.insert(test_name.clone(), current_raw_lines.clone());
-                current_test = None;
+            let test_name = self.current_test.take()?;
+            let raw_lines = std::mem::take(&mut self.current_raw_lines);
+            match parse_strategy_run_report(&test_name, self.current_first_line, raw_lines) {
+                Ok(report) => {
+                    let (index, _) = self.results.strategies.insert_full(test_name, report);
+                    return self
+                        .results
+                        .strategies
+                        .get_index(index)
+                        .map(|(name, _)| name.as_str());
+                }
+                Err(e) => self.error = Some(e),
             }
-        } else {
+        } else if self.current_test.is_some() {
             // Collect raw lines for the current test case
-            if let Some(_) = &current_test {
-                current_raw_lines.push(line.to_string());
-            }
+            self.current_raw_lines.push(line.to_string());
+        } else if let Err(message) = self.parse_header_line(line) {
+            self.error = Some(ParseError {
+                strategy: None,
+                section: Some("header"),
+                line_number: Some(self.line_number),
+                line: Some(line.to_string()),
+                message,
+            });
         }
+        None
     }
 
-    let mut parsed_reports: HashMap<String, StrategyRunReport> = HashMap::new();
-    // Parse the collected raw lines into StrategyRunReport
-    for (test_name, raw_lines) in test_case_map {
-        let parsed_report = parse_strategy_run_report(raw_lines);
+    fn parse_header_line(&mut self, line: &str) -> Result<(), String> {
+        let header = &mut self.results.header;
+        if line.starts_with("incentive ") {
+            header.incentive = Some(extract_u256(line)?);
+        } else if line.starts_with("liquidator ") {
+            header.liquidator = Some(extract_str(line)?);
+        } else if line.starts_with("treasuryPercent ") {
+            header.treasury_percent = Some(extract_u256(line)?);
+        }
+        Ok(())
+    }
 
-        parsed_reports.insert(test_name, parsed_report);
+    pub fn error(&self) -> Option<&ParseError> {
+        self.error.as_ref()
     }
 
-    LiquidationTestResults {
-        repeat: parsed_reports.get("repeatLiquidation").unwrap().clone(),
-        up_to_close_factor: parsed_reports
-            .get("upToCloseFactorLiquidation")
-            .unwrap()
-            .clone(),
-        drain: parsed_reports.get("drainLiquidation").unwrap().clone(),
-        large_borrow: parsed_reports.get("largestBorrow").unwrap().clone(),
-        drain_same_token: parsed_reports.get("drainSameToken").unwrap().clone(),
-        largest_cf_first: parsed_reports
-            .get("largestCollateralFactorFirst")
-            .unwrap()
-            .clone(),
-        smallest_cf_first: parsed_reports
-            .get("smallestCollateralFactorFirst")
-            .unwrap()
-            .clone(),
+    // The strategies completed so far, also when the output ends early
+    pub fn results(&self) -> &LiquidationTestResults {
+        &self.results
     }
+
+    pub fn finish(self) -> Result<LiquidationTestResults, ParseError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.results.strategies.is_empty() {
+            return Err(ParseError {
+                strategy: None,
+                section: None,
+                line_number: None,
+                line: None,
+                message: "No test cases in the output".to_string(),
+            });
+        }
+        Ok(self.results)
+    }
+}
+
+// Main log parser function
+pub fn parse_logs(logs: &str) -> Result<LiquidationTestResults, ParseError> {
+    let mut parser = LogParser::default();
+    for line in logs.lines() {
+        parser.push_line(line);
+    }
+    parser.finish()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_parse_logs() {
-        let logs = r#"
+    const LOGS: &str = r#"
   incentive 1100000000000000000
   liquidator 0x0000000000000000000000000000000000000000
   treasuryPercent 0
@@ -2805,15 +3045,55 @@ mod tests {
   Tests case end
         "#;
 
-        let result = parse_logs(logs);
+    #[test]
+    fn test_parse_logs() {
+        let result = parse_logs(LOGS).unwrap();
 
         println!("{:?}", result);
 
-        assert_eq!(result.repeat.assets.len(), 2);
+        let repeat = result.strategy(Strategy::Repeat).unwrap();
+        assert_eq!(repeat.assets.len(), 2);
         assert_eq!(
-            result.repeat.liquidations[0].collateral_v_token_gained,
+            repeat.liquidations[0].collateral_v_token_gained,
             U256::from_dec_str("11802590948156").unwrap(),
         );
+
+        // Known strategies keep their order, the rest is reported by name
+        assert_eq!(
+            result.strategies.keys().next().map(String::as_str),
+            Some("repeatLiquidation")
+        );
+        assert!(result.missing().is_empty());
+        assert!(result.unknown().is_empty());
+
+        assert_eq!(
+            result.header,
+            SimulationHeader {
+                incentive: Some(U256::from_dec_str("1100000000000000000").unwrap()),
+                liquidator: Some("0x0000000000000000000000000000000000000000".to_string()),
+                treasury_percent: Some(U256::zero()),
+            }
+        );
+        assert!(result.strategy(Strategy::Repeat).unwrap().steps.is_empty());
+        let drain_steps = &result.strategy(Strategy::Drain).unwrap().steps;
+        assert_eq!(drain_steps.len(), 14);
+        assert_eq!(
+            drain_steps[13],
+            LiquidationStepEvent {
+                repaid_usd: "6.222378829766267370".parse().unwrap(),
+                seized_usd: "6.844616355483320000".parse().unwrap(),
+                gas_fee_usd: "0.754706475000000000".parse().unwrap(),
+            }
+        );
+
+        // USD values and health factors serialize exactly like the test logged them
+        let repeat = serde_json::to_value(result.strategy(Strategy::Repeat).unwrap()).unwrap();
+        assert_eq!(repeat["profit_usd"], "93.895365798778689364");
+        assert_eq!(repeat["initial_health_factor"], "0.888549544472118463");
+        assert_eq!(
+            repeat["assets"][1]["initial_data"]["borrow_value_usd"],
+            "0.000000000000000000"
+        );
         // assert_eq!(
         //     repeat.collateral_v_token_gained_total,
         //     U256::from_dec_str("3437968650492").unwrap()
@@ -2870,4 +3150,85 @@ mod tests {
         //     U256::from_dec_str("16581453138495741007908").unwrap()
         // );
     }
+
+    #[test]
+    fn test_parse_results_json() {
+        let result = parse_logs(LOGS).unwrap();
+
+        // The results file has the shape of the serialized results, without the raw lines
+        let mut json = serde_json::to_value(&result).unwrap();
+        json["repeatLiquidation"]
+            .as_object_mut()
+            .unwrap()
+            .remove("raw");
+        let from_json = parse_results_json(&json.to_string()).unwrap();
+        let repeat = from_json.strategy(Strategy::Repeat).unwrap();
+        assert_eq!(
+            repeat.liquidations[0].collateral_v_token_gained,
+            U256::from_dec_str("11802590948156").unwrap(),
+        );
+        assert!(repeat.raw.is_empty());
+        assert!(parse_results_json("{\"repeatLiquidation\": {}}").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let logs = "Tests case: repeatLiquidation\nstrategyRunReport start\nasset start 0\ncash 12a\nasset end\nstrategyRunReport end\nTests case end\n";
+        let error = parse_logs(logs).unwrap_err();
+        assert_eq!(error.strategy.as_deref(), Some("repeatLiquidation"));
+        assert_eq!(error.section, Some("asset"));
+        assert_eq!(error.line_number, Some(4));
+        assert_eq!(error.line.as_deref(), Some("cash 12a"));
+        assert_eq!(
+            error.to_string(),
+            "`12a` is not a decimal number in repeatLiquidation asset section at line 4: `cash 12a`"
+        );
+
+        let error = parse_logs("incentive 1.1\nTests case: repeatLiquidation\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`1.1` is not a decimal number header section at line 1: `incentive 1.1`"
+        );
+        let error = parse_logs("Tests case: drainLiquidation\ndid a liquidation (repayUsd, seizedUsd, gasInUsd) 1.0 1.1\nTests case end\n").unwrap_err();
+        assert_eq!(error.section, Some("step"));
+        assert_eq!(error.message, "Expected 3 values, got 2");
+
+        let error = parse_logs("").unwrap_err();
+        assert_eq!(error.to_string(), "No test cases in the output");
+
+        // Strategies the aggregator does not know are kept, missing ones are not an error
+        let results = parse_logs("Tests case: newStrategy\nstrategyRunReport start\nprofitUsd 1.5\nstrategyRunReport end\nTests case end\n").unwrap();
+        assert_eq!(
+            results.unknown()["newStrategy"].profit_usd.to_string(),
+            "1.500000000000000000"
+        );
+        assert_eq!(results.missing(), Strategy::ALL);
+    }
+
+    #[test]
+    fn test_log_parser_streams() {
+        let mut parser = LogParser::default();
+        for line in [
+            "Tests case: repeatLiquidation",
+            "strategyRunReport start",
+            "profitUsd 1.5",
+            "strategyRunReport end",
+        ] {
+            assert_eq!(parser.push_line(line), None);
+        }
+        assert_eq!(
+            parser.push_line("Tests case end"),
+            Some("repeatLiquidation")
+        );
+
+        // Forge gets killed in the middle of the next strategy
+        parser.push_line("Tests case: drainLiquidation");
+        parser.push_line("strategyRunReport start");
+        let results = parser.results();
+        assert_eq!(results.strategies.len(), 1);
+        assert_eq!(
+            results.strategy(Strategy::Repeat).unwrap().profit_usd,
+            "1.5".parse().unwrap()
+        );
+    }
 }
@@ -0,0 +1,37 @@
+use {
+    crate::db_client::*,
+    clap::ValueEnum,
+    tracing::{info_span, Span},
+    tracing_subscriber::EnvFilter,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+// Logs go to stderr, stdout is left for the output of list, stats and single --no-insert
+pub fn init_logging(format: LogFormat, level: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        LogFormat::Human => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).init(),
+    }
+}
+
+// Everything logged while testing a liquidation carries these fields
+pub fn liquidation_span(data: &LiquidationData) -> Span {
+    info_span!(
+        "liquidation",
+        tx = %data.transaction_hash,
+        block = data.block_number,
+        borrower = %data.borrower,
+        v_token = %data.v_token,
+        v_token_collateral = %data.v_token_collateral,
+    )
+}
@@ -1,227 +1,1179 @@
 use {
+    clap::Parser,
+    deadpool_postgres::Pool,
     futures::stream::{self, StreamExt},
     std::collections::HashSet,
-    std::env,
-    std::error::Error as StdError,
-    std::process::Stdio,
+    std::fs,
+    std::path::Path,
     std::sync::Arc,
-    tokio::process::Command,
-    tokio::sync::{Mutex, Semaphore},
+    std::time::Instant,
+    tokio::sync::Mutex,
+    tracing::{error, info, info_span, warn, Instrument},
 };
 
+mod archive;
 mod big_num;
+mod block_queue;
+mod cli;
+mod concurrency;
+mod config;
 mod db_client;
+mod failures;
+mod forge;
+mod job_queue;
 mod log_parsing;
+mod logging;
 mod memory;
+mod metrics;
+mod plan;
+mod progress;
+mod reparse;
+mod retry;
+mod selection;
+mod shutdown;
+mod simulator;
+mod validation;
 
+pub use archive::*;
 pub use big_num::*;
+pub use block_queue::*;
+pub use cli::*;
+pub use concurrency::*;
+pub use config::*;
 pub use db_client::*;
+pub use failures::*;
+pub use forge::*;
+pub use job_queue::*;
 pub use log_parsing::*;
+pub use logging::*;
 pub use memory::*;
+pub use metrics::*;
+pub use plan::*;
+pub use progress::*;
+pub use reparse::*;
+pub use retry::*;
+pub use selection::*;
+pub use shutdown::*;
+pub use simulator::*;
+pub use validation::*;
 
-async fn run_forge_test(liquidation_data: &LiquidationData) -> Result<String, Box<dyn StdError>> {
-    println!("Runing test for tx {}", liquidation_data.transaction_hash);
-
-    // Get the current directory and move one level up
-    let current_dir = env::current_dir()?;
-    let parent_dir = current_dir
-        .parent()
-        .ok_or("Failed to get parent directory")?;
-
-    // Execute the command in the parent directory and wait for it to complete
-    let mut cmd = Command::new("forge")
-        .arg("test")
-        .arg("--no-rpc-rate-limit")
-        .arg("--match-test")
-        .arg("testLiquidations")
-        .arg("-vv")
-        .env("TX_HASH", &liquidation_data.transaction_hash)
-        .env("REPAY_V_TOKEN", &liquidation_data.v_token)
-        .env("BORROWER", &liquidation_data.borrower)
-        .env("REPAY_AMOUNT", liquidation_data.repay_amount.to_string())
-        .env("COLLATERAL_V_TOKEN", &liquidation_data.v_token_collateral)
-        .env("EXPECTED_SEIZE", liquidation_data.seize_tokens.to_string())
-        .env("GAS_PRICE", liquidation_data.gas_price.to_string())
-        .current_dir(parent_dir) // Set the working directory to the parent
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    // Monitor memory usage
-    let pid = cmd.id().expect("Failed to get child PID");
-    let mut sys = sysinfo::System::new();
-    // let mem_check = tokio::spawn(async move {
-
-    // });
+// Prefers the results file, the console output is parsed for tests and archives without one
+fn parse_forge_logs(mut output: ForgeOutput) -> Result<LiquidationTestResults, ForgeFailure> {
+    let parse_error = |message: String, output: ForgeOutput| ForgeFailure {
+        category: FailureCategory::ParseError,
+        message,
+        exit_code: Some(0),
+        stdout: output.stdout,
+        stdout_file: output.stdout_file,
+        stderr: output.stderr,
+        duration_ms: 0,
+        peak_memory_bytes: 0,
+        partial_results: Box::default(),
+    };
+
+    if let Some(results_json) = &output.results_json {
+        let mut parsed_data = match parse_results_json(results_json) {
+            Ok(parsed_data) => parsed_data,
+            Err(e) => return Err(parse_error(format!("Invalid results JSON: {}", e), output)),
+        };
+        // The header is only logged to the console
+        parsed_data.header = match output.parsed {
+            Some(Ok(parsed)) => parsed.header,
+            Some(Err(_)) => SimulationHeader::default(),
+            None => parse_logs(&output.stdout)
+                .map(|parsed| parsed.header)
+                .unwrap_or_default(),
+        };
+        return Ok(checked(parsed_data));
+    }
+
+    let parsed = match output.parsed.take() {
+        Some(parsed) => parsed,
+        None => parse_logs(&output.stdout),
+    };
+    let parsed_data = parsed.map_err(|e| parse_error(e.to_string(), output))?;
+    Ok(checked(parsed_data))
+}
+
+fn checked(mut parsed_data: LiquidationTestResults) -> LiquidationTestResults {
+    report_strategies(&parsed_data);
+    parsed_data.violations = validate(&parsed_data);
+    report_violations(&parsed_data.violations);
+    parsed_data
+}
+
+// A test that gained or lost a strategy still gets its results stored
+fn report_strategies(parsed_data: &LiquidationTestResults) {
+    let missing = parsed_data.missing();
+    if !missing.is_empty() {
+        let missing: Vec<&str> = missing
+            .iter()
+            .map(|strategy| strategy.test_name())
+            .collect();
+        warn!(
+            "Strategies missing from the forge output: {}",
+            missing.join(", ")
+        );
+    }
+    let unknown = parsed_data.unknown();
+    if !unknown.is_empty() {
+        let unknown: Vec<&str> = unknown.keys().copied().collect();
+        warn!(
+            "Unknown strategies stored in other_strategies: {}",
+            unknown.join(", ")
+        );
+    }
+}
+
+// Violations are stored with the results, an error points at a simulation or parser bug
+fn report_violations(violations: &[Violation]) {
+    for violation in violations {
+        METRICS
+            .validation_violations_total
+            .with_label_values(&[violation.check, violation.severity.as_str()])
+            .inc();
+        match violation.severity {
+            Severity::Error => error!(
+                strategy = violation.strategy,
+                check = violation.check,
+                "Inconsistent results: {}",
+                violation.message
+            ),
+            Severity::Warning => warn!(
+                strategy = violation.strategy,
+                check = violation.check,
+                "Inconsistent results: {}",
+                violation.message
+            ),
+        }
+    }
+}
 
+async fn record_failure(
+    store: &dyn JobStore,
+    transaction_hash: &str,
+    attempt: u32,
+    failure: &ForgeFailure,
+) {
+    if let Err(e) = store
+        .record_failure(transaction_hash, attempt, failure)
+        .await
+    {
+        error!("Error recording the failure: {}", e);
+    }
+}
+
+// What the liquidation tests of a command share
+struct TestContext<'a> {
+    config: &'a AggregatorConfig,
+    simulator: &'a dyn Simulator,
+    concurrency: Option<&'a ConcurrencyController>,
+    shutdown: &'a Shutdown,
+    archive: Option<&'a LogArchive>,
+}
+
+// Runs the simulation until it succeeds or fails in a way the retry policy does not retry.
+// Every failed attempt is recorded, unless there is no store to record it to.
+// A shutdown ends the retries with an interrupted failure.
+async fn run_with_retries(
+    store: Option<&dyn JobStore>,
+    data: &LiquidationData,
+    context: &TestContext<'_>,
+    slot: &mut ForgeSlot<'_>,
+) -> Result<LiquidationTestResults, ForgeFailure> {
+    let TestContext {
+        config,
+        simulator,
+        concurrency,
+        shutdown,
+        archive,
+    } = context;
+    let request = SimulationRequest::from(data);
+    let mut attempt = 1;
     loop {
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-
-        // if is_memory_under_pressure().await.unwrap_or(false) {
-        //     eprintln!("System under memory pressure (pageouts detected). Killing child.");
-        //     let _ = cmd.kill().await;
-        //     break;
-        // }
-
-        let child_status = cmd.try_wait();
-        if let Ok(None) = child_status {
-            // println!("Child did not finish yet {}", pid);
-            sys.refresh_process(sysinfo::Pid::from_u32(pid));
-            if let Some(proc) = sys.process(sysinfo::Pid::from_u32(pid)) {
-                let memory_bytes = proc.memory();
-                // println!("Child memory usage {}", memory_bytes);
-                if memory_bytes > 10 * 1024 * 1024 * 1024 {
-                    eprintln!(
-                        "(NOT) Killing child process {} due to memory > 10GB ({})",
-                        pid, memory_bytes
-                    );
-                    // let _ = cmd.kill().await;
-                    // break;
+        // Also after waiting for the block, a shutdown starts no new forge run
+        if shutdown.is_stopping() {
+            return Err(ForgeFailure::new(
+                FailureCategory::Interrupted,
+                "Shutdown before forge started".to_string(),
+            ));
+        }
+        let result = simulator.simulate(&request, shutdown).await;
+        if let Some(archive) = archive {
+            archive
+                .store(&data.transaction_hash, attempt, &result)
+                .await;
+        }
+        let result = result.and_then(parse_forge_logs);
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(failure) => failure.category.as_str(),
+        };
+        METRICS
+            .forge_outcomes_total
+            .with_label_values(&[outcome])
+            .inc();
+
+        let failure = match result {
+            Ok(parsed_data) => {
+                if let Some(concurrency) = concurrency {
+                    concurrency.record_attempt(None);
                 }
-            } else {
-                break; // process exited
+                return Ok(parsed_data);
             }
+            Err(failure) if failure.category == FailureCategory::Interrupted => {
+                return Err(failure)
+            }
+            Err(failure) => failure,
+        };
+        if let Some(concurrency) = concurrency {
+            concurrency.record_attempt(Some(failure.category));
+        }
+
+        warn!(attempt, category = %failure.category, "Forge test failed: {}", failure);
+        if let Some(store) = store {
+            record_failure(store, &data.transaction_hash, attempt, &failure).await;
+        }
 
-            continue;
-        } else {
-            break;
+        match config.retry.backoff(failure.category, attempt) {
+            Some(delay) => {
+                info!(?delay, "Retrying after {}", failure.category);
+                // Another job can run forge during the backoff
+                tokio::select! {
+                    _ = slot.release_while(tokio::time::sleep(delay)) => {}
+                    _ = shutdown.stopping() => {
+                        return Err(ForgeFailure {
+                            category: FailureCategory::Interrupted,
+                            message: format!("Shutdown while waiting to retry after {}", failure),
+                            ..failure
+                        });
+                    }
+                }
+                attempt += 1;
+            }
+            None => return Err(failure),
         }
     }
+}
+
+// Tests one leased liquidation and returns what its job ends up as
+async fn test_liquidation(
+    store: &dyn JobStore,
+    data: &LiquidationData,
+    context: &TestContext<'_>,
+    blocks: &BlockQueue,
+    slot: &mut ForgeSlot<'_>,
+) -> JobOutcome {
+    // Released when this returns, whatever the outcome. The forge slot is not held meanwhile.
+    let (_block_guard, waited_for_block) = slot.release_while(blocks.lock(data.block_number)).await;
+    if waited_for_block {
+        info!("Waited for another liquidation of the block to finish");
+    }
 
-    //     let output = {
-    //         use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    // Parse the logs and insert data into the database
+    let started = Instant::now();
+    let result = run_with_retries(Some(store), data, context, slot).await;
+    let simulation_time = started.elapsed();
 
-    // use std::ffi::OsStr;
-    // use std::future::Future;
-    // use std::io;
-    // use std::path::Path;
-    // use std::pin::Pin;
-    // use std::process::{Command as StdCommand, ExitStatus, Output, Stdio};
-    // use std::task::{ready, Context, Poll};
+    let parsed_data = match result {
+        Ok(parsed_data) => parsed_data,
+        Err(failure) => {
+            let state = match failure.category {
+                FailureCategory::Interrupted => JobState::Interrupted,
+                _ => JobState::Failed,
+            };
+            return JobOutcome {
+                state,
+                error: Some(failure.to_string()),
+                category: Some(failure.category),
+                simulation_time,
+                waited_for_block,
+            };
+        }
+    };
 
-    //         async fn read_to_end<A: AsyncRead + Unpin>(io: &mut Option<A>) -> io::Result<Vec<u8>> {
-    //             let mut vec = Vec::new();
-    //             if let Some(io) = io.as_mut() {
-    //                 tokio::io::util::read_to_end(io, &mut vec).await?;
-    //             }
-    //             Ok(vec)
-    //         }
+    if let Err(e) = store
+        .store_results(&data.transaction_hash, &parsed_data)
+        .await
+    {
+        error!("Error inserting data into database: {}", e);
+        return JobOutcome {
+            state: JobState::Failed,
+            error: Some(e.to_string()),
+            category: None,
+            simulation_time,
+            waited_for_block,
+        };
+    }
 
-    //         let mut stdout_pipe = cmd.stdout.take();
-    //         let mut stderr_pipe = cmd.stderr.take();
+    info!("Data inserted");
+    JobOutcome {
+        state: JobState::Succeeded,
+        error: None,
+        category: None,
+        simulation_time,
+        waited_for_block,
+    }
+}
 
-    //         let stdout_fut = read_to_end(&mut stdout_pipe);
-    //         let stderr_fut = read_to_end(&mut stderr_pipe);
+// Leases and tests jobs until the queue runs dry, `limit` jobs were leased or the shutdown
+// stops it. The leases of the running tests are renewed meanwhile.
+async fn process_jobs(
+    store: &dyn JobStore,
+    context: &TestContext<'_>,
+    concurrency: &ConcurrencyController,
+    memory_pressure: &dyn MemoryPressureSource,
+    limit: Option<usize>,
+    progress: &Progress,
+) {
+    let blocks = BlockQueue::default();
+    let leased = Mutex::new(HashSet::new());
 
-    //         let (status, stdout, stderr) = try_join3(self.wait(), stdout_fut, stderr_fut).await?;
+    let lease_renewal = async {
+        loop {
+            tokio::time::sleep(LEASE_RENEW_INTERVAL).await;
+            let transaction_hashes: Vec<String> = leased.lock().await.iter().cloned().collect();
+            if let Err(e) = store.renew(&transaction_hashes).await {
+                error!("Error renewing leases: {}", e);
+            }
+        }
+    };
 
-    //         // Drop happens after `try_join` due to <https://github.com/tokio-rs/tokio/issues/4309>
-    //         drop(stdout_pipe);
-    //         drop(stderr_pipe);
+    // Jobs are leased lazily, only when the controller has room for another forge test
+    // and the system is not short on memory
+    let jobs = stream::unfold(0, |leased_count| async move {
+        if limit.is_some_and(|limit| leased_count >= limit) {
+            return None;
+        }
+        let permit = tokio::select! {
+            permit = concurrency.acquire() => permit,
+            _ = context.shutdown.stopping() => return None,
+        };
+        tokio::select! {
+            _ = wait_for_memory_headroom(memory_pressure, &context.config.memory_pressure) => {}
+            _ = context.shutdown.stopping() => return None,
+        }
+        match store.lease().await {
+            Ok(Some(data)) => Some(((permit, data), leased_count + 1)),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Error leasing a job: {}", e);
+                None
+            }
+        }
+    });
 
-    //         Ok(Output {
-    //             status,
-    //             stdout,
-    //             stderr,
-    //         })
-    //     }
+    // The permits bound the concurrency, not the stream
+    let tests = jobs.for_each_concurrent(None, |(permit, data)| {
+        let span = liquidation_span(&data);
+        let blocks = &blocks;
+        let leased = &leased;
+        async move {
+            leased.lock().await.insert(data.transaction_hash.clone());
+            progress.start();
 
-    let output = cmd.wait_with_output().await?;
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            let mut slot = ForgeSlot::new(concurrency, permit);
+            let outcome = test_liquidation(store, &data, context, blocks, &mut slot).await;
+            if let Err(e) = store
+                .finish(
+                    &data.transaction_hash,
+                    outcome.state,
+                    outcome.error.as_deref(),
+                )
+                .await
+            {
+                error!("Error finishing the job: {}", e);
+            }
 
-    if output.status.success() {
-        Ok(stdout)
-    } else {
-        Err(format!(
-            "Command failed with status: {:?}\nStdout: {}\nStderr: {}",
-            output.status.code(),
-            "<no stdout>",
-            stderr
-        )
-        .into())
+            progress.finish(&data.transaction_hash, &outcome);
+            leased.lock().await.remove(&data.transaction_hash);
+        }
+        .instrument(span)
+    });
+
+    tokio::select! {
+        _ = tests => {}
+        _ = lease_renewal => {}
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let pool = Arc::new(create_pool());
+async fn update_queue_depth(pool: Arc<Pool>) {
+    match fetch_job_counts(pool).await {
+        Ok(counts) => {
+            for state in JobState::ALL {
+                let count = counts.get(state.as_str()).copied().unwrap_or(0);
+                METRICS
+                    .queue_depth
+                    .with_label_values(&[state.as_str()])
+                    .set(count);
+            }
+        }
+        Err(e) => error!("Error fetching job counts: {}", e),
+    }
+}
 
-    let liquidation_data = match fetch_liquidation_data(pool.clone()).await {
-        Ok(data) => data,
+async fn run(
+    pool: Arc<Pool>,
+    config: &AggregatorConfig,
+    selection: &SelectionQuery,
+    args: &RunArgs,
+) {
+    let limit = args.limit;
+
+    if let Err(e) = ensure_job_queue(pool.clone()).await {
+        error!("Error creating the job queue: {}", e);
+        return;
+    }
+    if let Err(e) = ensure_failures_table(pool.clone()).await {
+        error!("Error creating the failures table: {}", e);
+        return;
+    }
+    if let Err(e) = ensure_results_columns(pool.clone()).await {
+        error!("Error adding the results columns: {}", e);
+        return;
+    }
+
+    if args.retry_failed {
+        match retry_failed_jobs(pool.clone()).await {
+            Ok(count) => info!("Retrying {} failed jobs", count),
+            Err(e) => {
+                error!("Error resetting failed jobs: {}", e);
+                return;
+            }
+        }
+    }
+
+    if !args.no_enqueue {
+        let liquidation_data = match fetch_liquidation_data(pool.clone(), selection).await {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Error fetching liquidation data: {}", e);
+                return;
+            }
+        };
+        match enqueue_jobs(pool.clone(), &liquidation_data).await {
+            Ok(count) => info!(
+                "Queued {} of {} selected liquidations",
+                count,
+                liquidation_data.len()
+            ),
+            Err(e) => {
+                error!("Error queueing liquidations: {}", e);
+                return;
+            }
+        }
+    }
+
+    let queued = match fetch_job_counts(pool.clone()).await {
+        Ok(counts) => {
+            let leasable = [JobState::Pending, JobState::Interrupted]
+                .iter()
+                .map(|state| counts.get(state.as_str()).copied().unwrap_or(0) as u64)
+                .sum::<u64>();
+            // Same-block liquidations used to be skipped, enqueueing puts them back to pending
+            let skipped = counts.get(JobState::Skipped.as_str()).copied().unwrap_or(0);
+            if skipped > 0 {
+                warn!(
+                    skipped,
+                    "Jobs skipped by earlier runs are not tested, run without --no-enqueue to queue them again"
+                );
+            }
+            limit.map_or(leasable, |limit| leasable.min(limit as u64))
+        }
+        Err(e) => {
+            error!("Error fetching job counts: {}", e);
+            return;
+        }
+    };
+
+    let run_id = new_run_id();
+    let worker_id = worker_id();
+    info!(
+        "Starting run {} to analyze {} queued liquidations as {}",
+        run_id, queued, worker_id
+    );
+    let progress = Arc::new(Progress::new(&run_id, &worker_id, queued));
+
+    let simulator = match simulator(args.simulator, &config.limits) {
+        Ok(simulator) => simulator,
         Err(e) => {
-            eprintln!("Error fetching liquidation data: {}", e);
+            error!("Error creating the simulator: {}", e);
             return;
         }
     };
+    info!("Simulating with {}", simulator.name());
+    // Replayed output is in the archive already
+    let archive = match args.simulator {
+        SimulatorKind::Forge => {
+            prune_archive(&run_id, &config.archive);
+            open_archive(&run_id, &config.archive)
+        }
+        SimulatorKind::Replay => None,
+    };
 
-    println!(
-        "Starting to analyze {} liquidations",
-        liquidation_data.len(),
+    let shutdown = Shutdown::listen(&config.shutdown);
+
+    let memory_pressure: Arc<dyn MemoryPressureSource> = Arc::from(memory_pressure_source());
+    info!("Reading memory pressure from {}", memory_pressure.name());
+
+    let mut concurrency_config = config.concurrency.clone();
+    if let Some(max_workers) = args.workers {
+        concurrency_config.max = max_workers;
+    }
+    let concurrency = Arc::new(ConcurrencyController::new(concurrency_config));
+    info!(
+        "Starting with {} concurrent forge tests",
+        concurrency.target()
     );
 
-    let parallel_workers: usize = 16;
-    let semaphore = Arc::new(Semaphore::new(parallel_workers));
-    let active_blocks = Arc::new(Mutex::new(HashSet::new()));
+    let concurrency_adjustment = tokio::spawn({
+        let concurrency = Arc::clone(&concurrency);
+        let memory_pressure = Arc::clone(&memory_pressure);
+        let thresholds = config.memory_pressure.clone();
+        async move { concurrency.run(memory_pressure.as_ref(), &thresholds).await }
+    });
+
+    let metrics_server = args.metrics_addr.map(|addr| {
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(addr).await {
+                error!("Error serving metrics on {}: {}", addr, e);
+            }
+        })
+    });
+
+    let progress_reporter = tokio::spawn({
+        let pool = pool.clone();
+        let progress = Arc::clone(&progress);
+        async move {
+            loop {
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+                info!("{}", progress.report());
+                update_queue_depth(pool.clone()).await;
+            }
+        }
+    });
+
+    let context = &TestContext {
+        config,
+        simulator: simulator.as_ref(),
+        concurrency: Some(&concurrency),
+        shutdown: &shutdown,
+        archive: archive.as_ref(),
+    };
+    let store = PgJobStore::new(pool.clone(), &worker_id);
+    process_jobs(
+        &store,
+        context,
+        &concurrency,
+        memory_pressure.as_ref(),
+        limit,
+        &progress,
+    )
+    .await;
 
-    stream::iter(liquidation_data)
-        .for_each_concurrent(Some(parallel_workers), |data| {
-            let semaphore = Arc::clone(&semaphore);
-            let active_blocks = Arc::clone(&active_blocks);
+    concurrency_adjustment.abort();
+    progress_reporter.abort();
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+
+    let summary = progress.summary();
+    summary.print();
+    match summary.write() {
+        Ok(path) => info!("Summary written to {}", path.display()),
+        Err(e) => error!("Error writing the run summary: {}", e),
+    }
+}
+
+// Archives the forge output of the run
+fn open_archive(run_id: &str, config: &ArchiveConfig) -> Option<LogArchive> {
+    if !config.enabled {
+        return None;
+    }
+    match LogArchive::create(run_id) {
+        Ok(archive) => Some(archive),
+        Err(e) => {
+            error!("Error creating the forge log archive: {}", e);
+            None
+        }
+    }
+}
+
+async fn single(
+    pool: Arc<Pool>,
+    config: &AggregatorConfig,
+    transaction_hash: &str,
+    no_insert: bool,
+    simulator_kind: SimulatorKind,
+) {
+    let data = match fetch_liquidation_by_hash(pool.clone(), transaction_hash).await {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            error!("Liquidation {} not found", transaction_hash);
+            return;
+        }
+        Err(e) => {
+            error!("Error fetching liquidation data: {}", e);
+            return;
+        }
+    };
+
+    test_single_liquidation(pool, config, &data, no_insert, simulator_kind)
+        .instrument(liquidation_span(&data))
+        .await
+}
+
+async fn test_single_liquidation(
+    pool: Arc<Pool>,
+    config: &AggregatorConfig,
+    data: &LiquidationData,
+    no_insert: bool,
+    simulator_kind: SimulatorKind,
+) {
+    let simulator = match simulator(simulator_kind, &config.limits) {
+        Ok(simulator) => simulator,
+        Err(e) => {
+            error!("Error creating the simulator: {}", e);
+            return;
+        }
+    };
+
+    let store = if no_insert {
+        None
+    } else {
+        if let Err(e) = ensure_failures_table(pool.clone()).await {
+            error!("Error creating the failures table: {}", e);
+        }
+        if let Err(e) = ensure_results_columns(pool.clone()).await {
+            error!("Error adding the results columns: {}", e);
+        }
+        Some(PgJobStore::new(pool, &worker_id()))
+    };
+
+    let archive = match simulator_kind {
+        SimulatorKind::Forge => open_archive(SINGLE_RUN_ID, &config.archive),
+        SimulatorKind::Replay => None,
+    };
+    let shutdown = Shutdown::listen(&config.shutdown);
+    let context = TestContext {
+        config,
+        simulator: simulator.as_ref(),
+        concurrency: None,
+        shutdown: &shutdown,
+        archive: archive.as_ref(),
+    };
+    let mut slot = ForgeSlot::default();
+    let store = store.as_ref().map(|store| store as &dyn JobStore);
+    let parsed_data = match run_with_retries(store, data, &context, &mut slot).await {
+        Ok(parsed_data) => parsed_data,
+        Err(failure) => {
+            error!(
+                stdout = %failure.stdout_tail(),
+                stderr = %failure.stderr_tail(),
+                "Forge test failed"
+            );
+            return;
+        }
+    };
+
+    let Some(store) = store else {
+        println!("{}", serde_json::to_string_pretty(&parsed_data).unwrap());
+        return;
+    };
+
+    if let Err(e) = store
+        .store_results(&data.transaction_hash, &parsed_data)
+        .await
+    {
+        error!("Error inserting data into database: {}", e);
+        return;
+    }
+
+    info!("Data inserted");
+}
+
+async fn list(pool: Arc<Pool>, selection: &SelectionQuery, limit: Option<usize>) {
+    let liquidation_data = match fetch_liquidation_data(pool, selection).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error fetching liquidation data: {}", e);
+            return;
+        }
+    };
+
+    let total = liquidation_data.len();
+    for data in liquidation_data.iter().take(limit.unwrap_or(total)) {
+        println!(
+            "{} {} {} {} {}",
+            data.transaction_hash,
+            data.block_number,
+            data.v_token,
+            data.v_token_collateral,
+            data.borrower,
+        );
+    }
+    println!("{} liquidations selected", total);
+}
+
+async fn plan(
+    pool: Arc<Pool>,
+    selection_config: &SelectionConfig,
+    selection: &SelectionQuery,
+    csv: Option<&Path>,
+) {
+    let rows = match fetch_selection_plan_rows(pool, selection).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Error fetching liquidations: {}", e);
+            return;
+        }
+    };
+
+    let plan = SelectionPlan::new(rows, &selection_config.markets);
+    plan.print();
+
+    if let Some(path) = csv {
+        match fs::write(path, plan.to_csv()) {
+            Ok(()) => info!("Selected liquidations written to {}", path.display()),
+            Err(e) => error!("Error writing {}: {}", path.display(), e),
+        }
+    }
+}
+
+async fn reparse(
+    pool: Arc<Pool>,
+    transaction_hashes: &[String],
+    parallel_workers: usize,
+    source: ReparseSource,
+    dry_run: bool,
+) {
+    if let Err(e) = ensure_results_columns(pool.clone()).await {
+        error!("Error adding the results columns: {}", e);
+        return;
+    }
+
+    let inputs: Vec<(String, ReparseInput)> = match source {
+        ReparseSource::Archive => match latest_archived_outputs(transaction_hashes) {
+            Ok(outputs) => outputs
+                .into_iter()
+                .map(|(dir, entry)| {
+                    (
+                        entry.transaction_hash.clone(),
+                        ReparseInput::Archived(dir, entry),
+                    )
+                })
+                .collect(),
+            Err(e) => {
+                error!("Error reading the forge log archive: {}", e);
+                return;
+            }
+        },
+        ReparseSource::Stored => match fetch_stored_logs(pool.clone(), transaction_hashes).await {
+            Ok(logs) => logs
+                .into_iter()
+                .map(|(transaction_hash, logs)| (transaction_hash, ReparseInput::Stored(logs)))
+                .collect(),
+            Err(e) => {
+                error!("Error fetching stored logs: {}", e);
+                return;
+            }
+        },
+    };
+
+    info!("Reparsing {} liquidations", inputs.len());
+    let diff = Arc::new(std::sync::Mutex::new(ReparseDiff::default()));
+
+    stream::iter(inputs)
+        .for_each_concurrent(Some(parallel_workers), |(transaction_hash, input)| {
             let pool_clone = pool.clone();
+            let diff = Arc::clone(&diff);
+            let span = info_span!("reparse", tx = %transaction_hash);
             async move {
-                // Wait if memory is above threshold
-                wait_if_memory_high(30).await;
-
-                let _permit = match semaphore.acquire().await {
-                    Ok(permit) => permit,
+                // Reading and parsing are CPU bound, the blocking pool runs them in parallel
+                let parsed = tokio::task::spawn_blocking(move || {
+                    let output = input.load().map_err(|e| e.to_string())?;
+                    parse_forge_logs(output).map_err(|failure| failure.to_string())
+                })
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|parsed| parsed);
+                let parsed_data = match parsed {
+                    Ok(parsed_data) => parsed_data,
                     Err(e) => {
-                        eprintln!("Error acquiring semaphore: {}", e);
+                        error!("Error reparsing: {}", e);
+                        diff.lock().unwrap().failed += 1;
                         return;
                     }
                 };
 
-                let mut active_blocks_guard = active_blocks.lock().await;
-                if active_blocks_guard.insert(data.block_number) {
-                    drop(active_blocks_guard); // Release the lock as soon as possible
-                } else {
-                    // If the block_number is already in the set, skip processing
-                    println!(
-                        "Skipping duplicate test for block number {}. Tx {}",
-                        data.block_number, data.transaction_hash,
-                    );
-                    return;
-                }
-
-                let logs = match run_forge_test(&data).await {
-                    Ok(logs) => logs,
+                let stored = match fetch_stored_results(pool_clone.clone(), &transaction_hash).await
+                {
+                    Ok(stored) => stored,
                     Err(e) => {
-                        eprintln!(
-                            "Error running forge test for {}: {}",
-                            data.transaction_hash, e
-                        );
+                        error!("Error fetching stored results: {}", e);
+                        diff.lock().unwrap().failed += 1;
                         return;
                     }
                 };
+                // The header and violations are compared too, they are stored with the results
+                let reparsed = results_to_store(&parsed_data, stored.as_ref()).unwrap();
+                diff.lock().unwrap().record(stored.as_ref(), &reparsed);
+                if dry_run || stored.as_ref() == Some(&reparsed) {
+                    return;
+                }
 
-                // Parse the logs and insert data into the database
-                let parsed_data = parse_logs(&logs);
                 if let Err(e) =
-                    insert_with_retries(pool_clone, &data.transaction_hash, &parsed_data).await
+                    insert_with_retries(pool_clone, &transaction_hash, &parsed_data).await
                 {
-                    eprintln!(
-                        "Error inserting data into database for {}: {}",
-                        data.transaction_hash, e
-                    );
+                    error!("Error inserting data into database: {}", e);
                     return;
                 }
 
-                let mut active_blocks_guard = active_blocks.lock().await;
-                active_blocks_guard.remove(&data.block_number);
-                drop(active_blocks_guard);
+                info!("Data reparsed");
+            }
+            .instrument(span)
+        })
+        .await;
+
+    diff.lock().unwrap().print();
+}
 
-                println!("Data inserted for {}", data.transaction_hash);
+async fn stats(pool: Arc<Pool>, selection: &SelectionQuery) {
+    let stats = match fetch_stats(pool.clone()).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Error fetching stats: {}", e);
+            return;
+        }
+    };
+    let pending = match fetch_liquidation_data(pool.clone(), selection).await {
+        Ok(data) => data.len(),
+        Err(e) => {
+            error!("Error fetching liquidation data: {}", e);
+            return;
+        }
+    };
+
+    println!("Liquidations: {}", stats.liquidations);
+    println!("Tested:       {}", stats.tested);
+    println!("Left to test: {}", pending);
+
+    match fetch_job_counts(pool).await {
+        Ok(counts) => {
+            for state in JobState::ALL {
+                let count = counts.get(state.as_str()).copied().unwrap_or(0);
+                println!("Jobs {:<10} {}", state.as_str(), count);
             }
+        }
+        Err(e) => error!("Error fetching job counts: {}", e),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    init_logging(cli.log_format, &cli.log_level);
+    let pool = Arc::new(create_pool());
+
+    let config = match AggregatorConfig::load(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Error loading config: {}", e);
+            return;
+        }
+    };
+    let selection_config = match SelectionConfig::load(&cli.selection) {
+        Ok(selection_config) => selection_config,
+        Err(e) => {
+            error!("Error loading selection: {}", e);
+            return;
+        }
+    };
+    let selection = match selection_config.compile() {
+        Ok(selection) => selection,
+        Err(e) => {
+            error!("Error loading selection: {}", e);
+            return;
+        }
+    };
+
+    match cli.command {
+        Commands::Run(args) => run(pool, &config, &selection, &args).await,
+        Commands::Single {
+            tx_hash,
+            no_insert,
+            simulator,
+        } => single(pool, &config, &tx_hash, no_insert, simulator).await,
+        Commands::List { limit } => list(pool, &selection, limit).await,
+        Commands::Plan { csv } => plan(pool, &selection_config, &selection, csv.as_deref()).await,
+        Commands::Reparse {
+            tx_hashes,
+            workers,
+            source,
+            dry_run,
+        } => reparse(pool, &tx_hashes, workers, source, dry_run).await,
+        Commands::Stats => stats(pool, &selection).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        async_trait::async_trait,
+        std::{collections::VecDeque, error::Error as StdError, time::Duration},
+    };
+
+    fn liquidation(transaction_hash: &str) -> LiquidationData {
+        LiquidationData {
+            transaction_hash: transaction_hash.to_string(),
+            block_number: 35490444,
+            v_token: "0x95c78222B3D6e262426483D42CfA53685A67Ab9D".to_string(),
+            borrower: "0xb0b".to_string(),
+            repay_amount: U256::from(1000),
+            v_token_collateral: "0x78366446547D062f45b4C0f320cDaa6d710D87bb".to_string(),
+            seize_tokens: U256::from(10),
+            gas_price: U256::from(5000000000u64),
+        }
+    }
+
+    // Leases in queue order and keeps what came of each job
+    #[derive(Default)]
+    struct MemoryJobStore {
+        pending: std::sync::Mutex<VecDeque<LiquidationData>>,
+        finished: std::sync::Mutex<Vec<(String, JobState)>>,
+    }
+
+    #[async_trait]
+    impl JobStore for MemoryJobStore {
+        async fn lease(&self) -> Result<Option<LiquidationData>, Box<dyn StdError>> {
+            Ok(self.pending.lock().unwrap().pop_front())
+        }
+
+        async fn renew(&self, _transaction_hashes: &[String]) -> Result<(), Box<dyn StdError>> {
+            Ok(())
+        }
+
+        async fn finish(
+            &self,
+            transaction_hash: &str,
+            state: JobState,
+            _error: Option<&str>,
+        ) -> Result<(), Box<dyn StdError>> {
+            let finished = (transaction_hash.to_string(), state);
+            self.finished.lock().unwrap().push(finished);
+            Ok(())
+        }
+
+        async fn record_failure(
+            &self,
+            _transaction_hash: &str,
+            _attempt: u32,
+            _failure: &ForgeFailure,
+        ) -> Result<(), Box<dyn StdError>> {
+            Ok(())
+        }
+
+        async fn store_results(
+            &self,
+            _transaction_hash: &str,
+            _parsed_data: &LiquidationTestResults,
+        ) -> Result<(), Box<dyn StdError>> {
+            Ok(())
+        }
+    }
+
+    struct NoMemoryPressure;
+
+    #[async_trait]
+    impl MemoryPressureSource for NoMemoryPressure {
+        fn name(&self) -> &'static str {
+            "none"
+        }
+
+        async fn sample(&self) -> Result<MemoryPressure, Box<dyn StdError>> {
+            Ok(MemoryPressure::default())
+        }
+    }
+
+    fn controller(max: usize) -> ConcurrencyController {
+        ConcurrencyController::new(ConcurrencyConfig {
+            min: max,
+            max,
+            initial: max,
+            ..Default::default()
         })
+    }
+
+    // Queues the liquidations with a successful simulation each
+    fn queue(simulator: &ScriptedSimulator, liquidations: &[(&str, i64)]) -> MemoryJobStore {
+        let store = MemoryJobStore::default();
+        for (transaction_hash, block_number) in liquidations {
+            let output = ForgeOutput {
+                results_json: Some("{}".to_string()),
+                ..Default::default()
+            };
+            simulator.push(transaction_hash, Ok(output));
+            store.pending.lock().unwrap().push_back(LiquidationData {
+                block_number: *block_number,
+                ..liquidation(transaction_hash)
+            });
+        }
+        store
+    }
+
+    #[tokio::test]
+    async fn test_process_jobs() {
+        let config: AggregatorConfig = toml::from_str("").unwrap();
+        let simulator = ScriptedSimulator::new(Duration::from_millis(50));
+        let shutdown = Shutdown::default();
+        let concurrency = controller(2);
+        let context = TestContext {
+            config: &config,
+            simulator: &simulator,
+            concurrency: Some(&concurrency),
+            shutdown: &shutdown,
+            archive: None,
+        };
+        let progress = Progress::new("test", "test", 4);
+
+        // 0x2 waits for 0x1 without its permit, so 0x3 runs next to 0x1
+        let store = queue(
+            &simulator,
+            &[("0x1", 1), ("0x2", 1), ("0x3", 2), ("0x4", 3)],
+        );
+        process_jobs(
+            &store,
+            &context,
+            &concurrency,
+            &NoMemoryPressure,
+            None,
+            &progress,
+        )
+        .await;
+        let mut finished = store.finished.lock().unwrap().clone();
+        finished.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            finished,
+            ["0x1", "0x2", "0x3", "0x4"].map(|tx| (tx.to_string(), JobState::Succeeded))
+        );
+        assert_eq!(*simulator.max_running.lock().unwrap(), 2);
+        assert!(!*simulator.same_block_overlapped.lock().unwrap());
+
+        // The limit stops leasing, running tests still finish
+        let store = queue(&simulator, &[("0x5", 4), ("0x6", 5), ("0x7", 6)]);
+        process_jobs(
+            &store,
+            &context,
+            &concurrency,
+            &NoMemoryPressure,
+            Some(2),
+            &progress,
+        )
         .await;
+        assert_eq!(store.finished.lock().unwrap().len(), 2);
+        assert_eq!(store.pending.lock().unwrap().len(), 1);
+
+        // A shutdown stops leasing, the tests already running finish. 0x9 waited for 0x8
+        // and is interrupted instead of starting forge.
+        let store = queue(
+            &simulator,
+            &[("0x8", 7), ("0x9", 7), ("0xa", 8), ("0xb", 9)],
+        );
+        tokio::join!(
+            process_jobs(
+                &store,
+                &context,
+                &concurrency,
+                &NoMemoryPressure,
+                None,
+                &progress,
+            ),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                shutdown.stop();
+            }
+        );
+        let mut finished = store.finished.lock().unwrap().clone();
+        finished.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            finished,
+            [
+                ("0x8".to_string(), JobState::Succeeded),
+                ("0x9".to_string(), JobState::Interrupted),
+                ("0xa".to_string(), JobState::Succeeded),
+            ]
+        );
+        assert_eq!(store.pending.lock().unwrap().len(), 1);
+        assert!(!simulator.calls.lock().unwrap().contains(&"0x9".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_with_retries() {
+        let config: AggregatorConfig = toml::from_str(
+            "[retry.rpc_rate_limit]\nmax_attempts = 3\nbase_delay_secs = 0.05\nmax_delay_secs = 0.05",
+        )
+        .unwrap();
+        let simulator = ScriptedSimulator::new(Duration::ZERO);
+        let shutdown = Shutdown::default();
+        let context = TestContext {
+            config: &config,
+            simulator: &simulator,
+            concurrency: None,
+            shutdown: &shutdown,
+            archive: None,
+        };
+
+        // Transient failures are retried, the first permanent one ends the test
+        simulator.push(
+            "0x1",
+            Err(ForgeFailure::new(
+                FailureCategory::RpcRateLimit,
+                "429".to_string(),
+            )),
+        );
+        simulator.push(
+            "0x1",
+            Err(ForgeFailure::new(
+                FailureCategory::AssertionFailed,
+                "[FAIL]".to_string(),
+            )),
+        );
+        let slot = &mut ForgeSlot::default();
+        let failure = run_with_retries(None, &liquidation("0x1"), &context, slot)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.category, FailureCategory::AssertionFailed);
+        assert_eq!(simulator.calls.lock().unwrap().len(), 2);
+
+        // Unparseable output of a successful run is a failure of its own
+        simulator.push("0x2", Ok(ForgeOutput::default()));
+        let failure = run_with_retries(None, &liquidation("0x2"), &context, slot)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.category, FailureCategory::ParseError);
+
+        // A shutdown ends the backoff instead of running again
+        simulator.push(
+            "0x3",
+            Err(ForgeFailure::new(
+                FailureCategory::RpcRateLimit,
+                "429".to_string(),
+            )),
+        );
+        let data = liquidation("0x3");
+        let (result, _) = tokio::join!(run_with_retries(None, &data, &context, slot), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            shutdown.stop();
+        });
+        assert_eq!(result.unwrap_err().category, FailureCategory::Interrupted);
+        assert_eq!(simulator.calls.lock().unwrap().len(), 4);
+
+        // Once stopping, forge isn't started at all
+        let failure = run_with_retries(None, &liquidation("0x4"), &context, slot)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.category, FailureCategory::Interrupted);
+        assert_eq!(simulator.calls.lock().unwrap().len(), 4);
+    }
 }
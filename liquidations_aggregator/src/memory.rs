@@ -1,67 +1,272 @@
 use std::time::Duration;
-use sysinfo::System;
+use sysinfo::{Pid, ProcessRefreshKind, System};
 use tokio::time::sleep;
+use tracing::{info, warn};
 
-pub async fn wait_if_memory_high(threshold_gb: u64) {
-    let mut sys = System::new();
-    loop {
-        sys.refresh_process(sysinfo::get_current_pid().unwrap());
-        let current_pid = sysinfo::get_current_pid().unwrap();
-        let process = sys.process(current_pid).unwrap();
+pub const GB: u64 = 1024 * 1024 * 1024;
 
-        let used_bytes = process.memory() * 1024; // in bytes
-        let threshold_bytes = threshold_gb * 1024 * 1024 * 1024;
+// Refreshes the memory of every process, the tree of a process can't be found otherwise
+pub fn refresh_process_memory(sys: &mut System) {
+    sys.refresh_processes_specifics(ProcessRefreshKind::new().with_memory());
+}
 
-        println!("Memory usage({} GB)", used_bytes / 1024 / 1024 / 1024);
+// The process followed by all of its descendants, parents before children
+pub fn process_tree(sys: &System, root: Pid) -> Vec<Pid> {
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            sys.processes()
+                .iter()
+                .filter(|(_, process)| process.parent() == Some(parent))
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+    tree.retain(|pid| sys.process(*pid).is_some());
+    tree
+}
 
-        // let child_count = sys
-        //     .processes()
-        //     .values()
-        //     .filter(|process| process.parent() == Some(current_pid))
-        //     .count();
+// Resident memory in bytes of the process and all of its descendants
+pub fn process_tree_memory(sys: &System, root: Pid) -> u64 {
+    process_tree(sys, root)
+        .iter()
+        .filter_map(|pid| sys.process(*pid))
+        .map(|process| process.memory())
+        .sum()
+}
 
-        // println!(
-        //     "Child processes running: {}, current_pid: {}",
-        //     child_count, current_pid
-        // );
+// Kills children before their parents so nothing gets reparented and left running
+pub fn kill_process_tree(sys: &System, root: Pid) {
+    for pid in process_tree(sys, root).iter().rev() {
+        if let Some(process) = sys.process(*pid) {
+            process.kill();
+        }
+    }
+}
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::error::Error as StdError;
+use std::sync::Mutex;
+use std::{fs, str};
+use tokio::process::Command;
+
+// One reading of how hard the system is pressed for memory. Backends fill in what they can.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryPressure {
+    // Share of the last 10s some tasks stalled waiting for memory, in percent (Linux PSI)
+    pub some_avg10: Option<f64>,
+    pub full_avg10: Option<f64>,
+    pub available_bytes: Option<u64>,
+    // Pages were written out since the previous sample (macOS)
+    pub swapping: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryPressureThresholds {
+    pub max_some_avg10: f64,
+    pub min_available_gb: f64,
+}
 
-        if used_bytes < threshold_bytes {
-            break;
+impl Default for MemoryPressureThresholds {
+    fn default() -> Self {
+        MemoryPressureThresholds {
+            max_some_avg10: 10.0,
+            min_available_gb: 4.0,
         }
+    }
+}
 
-        println!(
-            "Memory usage too high ({} GB), pausing...",
-            used_bytes / 1024 / 1024 / 1024
-        );
-        sleep(Duration::from_millis(500)).await;
+impl MemoryPressure {
+    pub fn is_under_pressure(&self, thresholds: &MemoryPressureThresholds) -> bool {
+        let min_available_bytes = (thresholds.min_available_gb * GB as f64) as u64;
+        self.swapping
+            || self
+                .some_avg10
+                .is_some_and(|avg| avg > thresholds.max_some_avg10)
+            || self
+                .available_bytes
+                .is_some_and(|available| available < min_available_bytes)
     }
 }
 
-use std::str;
-use tokio::process::Command;
+#[async_trait]
+pub trait MemoryPressureSource: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn sample(&self) -> Result<MemoryPressure, Box<dyn StdError>>;
+}
+
+// Picks the best backend the machine supports
+pub fn memory_pressure_source() -> Box<dyn MemoryPressureSource> {
+    if fs::metadata(PSI_MEMORY).is_ok() {
+        Box::new(LinuxPsi)
+    } else if cfg!(target_os = "macos") {
+        Box::new(MacVmStat::default())
+    } else {
+        Box::new(SysinfoAvailable)
+    }
+}
+
+const PSI_MEMORY: &str = "/proc/pressure/memory";
+const MEMINFO: &str = "/proc/meminfo";
+
+// Pressure stall information, Linux 4.20+
+pub struct LinuxPsi;
+
+#[async_trait]
+impl MemoryPressureSource for LinuxPsi {
+    fn name(&self) -> &'static str {
+        "psi"
+    }
+
+    async fn sample(&self) -> Result<MemoryPressure, Box<dyn StdError>> {
+        let (some_avg10, full_avg10) = parse_psi(&fs::read_to_string(PSI_MEMORY)?)?;
+        Ok(MemoryPressure {
+            some_avg10: Some(some_avg10),
+            full_avg10: Some(full_avg10),
+            available_bytes: parse_mem_available(&fs::read_to_string(MEMINFO)?),
+            swapping: false,
+        })
+    }
+}
+
+// some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+// full avg10=0.00 avg60=0.00 avg300=0.00 total=0
+fn parse_psi(content: &str) -> Result<(f64, f64), Box<dyn StdError>> {
+    let avg10 = |kind: &str| -> Result<f64, Box<dyn StdError>> {
+        let line = content
+            .lines()
+            .find(|line| line.starts_with(kind))
+            .ok_or_else(|| format!("No `{}` line in {}", kind, PSI_MEMORY))?;
+        let value = line
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("avg10="))
+            .ok_or_else(|| format!("No avg10 in `{}`", line))?;
+        Ok(value.parse()?)
+    };
+    Ok((avg10("some")?, avg10("full")?))
+}
+
+// MemAvailable:   12345678 kB
+fn parse_mem_available(content: &str) -> Option<u64> {
+    let line = content
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[derive(Default)]
+pub struct MacVmStat {
+    last_pageouts: Mutex<Option<u64>>,
+}
 
-pub async fn is_memory_under_pressure() -> Result<bool, Box<dyn std::error::Error>> {
-    let output = Command::new("vm_stat").output().await?;
-
-    let stdout = str::from_utf8(&output.stdout)?;
-    println!("{}", stdout);
-    for line in stdout.lines() {
-        if line.contains("Pageouts") {
-            let parts: Vec<&str> = line.split(':').collect();
-            if parts.len() == 2 {
-                let val = parts[1]
-                    .trim()
-                    .trim_end_matches('.')
-                    .replace(".", "")
-                    .replace(",", "");
-                if let Ok(pageouts) = val.parse::<u64>() {
-                    if pageouts > 0 {
-                        return Ok(true); // Swapping has occurred => memory pressure
-                    }
-                }
+#[async_trait]
+impl MemoryPressureSource for MacVmStat {
+    fn name(&self) -> &'static str {
+        "vm_stat"
+    }
+
+    async fn sample(&self) -> Result<MemoryPressure, Box<dyn StdError>> {
+        let output = Command::new("vm_stat").output().await?;
+        let stdout = str::from_utf8(&output.stdout)?;
+
+        let page_size = stdout
+            .lines()
+            .next()
+            .and_then(|header| header.split("page size of ").nth(1))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|size| size.parse::<u64>().ok())
+            .unwrap_or(4096);
+        let pages = |name: &str| -> Option<u64> {
+            let line = stdout.lines().find(|line| line.starts_with(name))?;
+            let value = line.split(':').nth(1)?.trim().trim_end_matches('.');
+            value.replace([',', '.'], "").parse().ok()
+        };
+
+        // Pageouts only ever grow, only new ones mean we are swapping now
+        let pageouts = pages("Pageouts");
+        let mut last_pageouts = self.last_pageouts.lock().unwrap();
+        let swapping = matches!((*last_pageouts, pageouts), (Some(last), Some(now)) if now > last);
+        *last_pageouts = pageouts;
+
+        let available_pages = ["Pages free", "Pages inactive", "Pages speculative"]
+            .iter()
+            .filter_map(|name| pages(name))
+            .sum::<u64>();
+
+        Ok(MemoryPressure {
+            some_avg10: None,
+            full_avg10: None,
+            available_bytes: Some(available_pages * page_size),
+            swapping,
+        })
+    }
+}
+
+pub struct SysinfoAvailable;
+
+#[async_trait]
+impl MemoryPressureSource for SysinfoAvailable {
+    fn name(&self) -> &'static str {
+        "sysinfo"
+    }
+
+    async fn sample(&self) -> Result<MemoryPressure, Box<dyn StdError>> {
+        let mut sys = System::new();
+        sys.refresh_memory();
+        Ok(MemoryPressure {
+            available_bytes: Some(sys.available_memory()),
+            ..Default::default()
+        })
+    }
+}
+
+// Holds back starting another forge process while the system is short on memory
+pub async fn wait_for_memory_headroom(
+    source: &dyn MemoryPressureSource,
+    thresholds: &MemoryPressureThresholds,
+) {
+    loop {
+        let pressure = match source.sample().await {
+            Ok(pressure) => pressure,
+            Err(e) => {
+                warn!(
+                    source = source.name(),
+                    "Error reading memory pressure: {}", e
+                );
+                return;
             }
+        };
+        if !pressure.is_under_pressure(thresholds) {
+            return;
         }
+
+        info!(?pressure, "Memory under pressure, pausing");
+        sleep(Duration::from_secs(5)).await;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(false)
+    #[test]
+    fn test_parse_psi_and_meminfo() {
+        let psi = "some avg10=12.50 avg60=3.10 avg300=0.80 total=123456\nfull avg10=1.25 avg60=0.30 avg300=0.05 total=2345\n";
+        assert_eq!(parse_psi(psi).unwrap(), (12.5, 1.25));
+
+        let meminfo = "MemTotal:       65755580 kB\nMemFree:         1234567 kB\nMemAvailable:    8388608 kB\n";
+        assert_eq!(parse_mem_available(meminfo), Some(8 * GB));
+
+        let pressure = MemoryPressure {
+            some_avg10: Some(12.5),
+            available_bytes: Some(8 * GB),
+            ..Default::default()
+        };
+        assert!(pressure.is_under_pressure(&MemoryPressureThresholds::default()));
+    }
 }
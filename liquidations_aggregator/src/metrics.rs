@@ -0,0 +1,211 @@
+use {
+    prometheus::{
+        exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+        IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+    },
+    std::{error::Error as StdError, net::SocketAddr, sync::LazyLock},
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    tracing::{info, warn},
+};
+
+// Recorded whether or not the endpoint is served, updating them is cheap
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub forge_run_duration_seconds: HistogramVec,
+    pub forge_outcomes_total: IntCounterVec,
+    pub forge_peak_memory_bytes: Histogram,
+    pub db_insert_duration_seconds: Histogram,
+    pub db_insert_retries_total: IntCounter,
+    pub validation_violations_total: IntCounterVec,
+    pub concurrency_target: IntGauge,
+    pub jobs_running: IntGauge,
+    pub queue_depth: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("liquidations_aggregator".to_string()), None)
+            .expect("Valid metrics prefix");
+
+        // 1s .. ~4.5h
+        let forge_run_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "forge_run_duration_seconds",
+                "Wall-clock time of one forge run",
+            )
+            .buckets(exponential_buckets(1.0, 2.0, 15).unwrap()),
+            &["outcome"],
+        )
+        .unwrap();
+        let forge_outcomes_total = IntCounterVec::new(
+            Opts::new(
+                "forge_outcomes_total",
+                "Forge attempts by outcome, success or the failure category",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        // 256 MB .. 64 GB
+        let forge_peak_memory_bytes = Histogram::with_opts(
+            HistogramOpts::new(
+                "forge_peak_memory_bytes",
+                "Peak resident memory of the forge process tree per run",
+            )
+            .buckets(exponential_buckets(256.0 * 1024.0 * 1024.0, 2.0, 9).unwrap()),
+        )
+        .unwrap();
+        let db_insert_duration_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_insert_duration_seconds",
+                "Time to store the results of one liquidation, including retries",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 14).unwrap()),
+        )
+        .unwrap();
+        let db_insert_retries_total = IntCounter::new(
+            "db_insert_retries_total",
+            "Failed result inserts that were retried",
+        )
+        .unwrap();
+        let validation_violations_total = IntCounterVec::new(
+            Opts::new(
+                "validation_violations_total",
+                "Inconsistencies found in parsed strategy reports",
+            ),
+            &["check", "severity"],
+        )
+        .unwrap();
+        let concurrency_target = IntGauge::new(
+            "concurrency_target",
+            "Forge tests allowed to run at the same time",
+        )
+        .unwrap();
+        let jobs_running =
+            IntGauge::new("jobs_running", "Jobs being tested by this process").unwrap();
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("queue_depth", "Jobs in liquidation_test_jobs by state"),
+            &["state"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(forge_run_duration_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(forge_outcomes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(forge_peak_memory_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_insert_duration_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_insert_retries_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(validation_violations_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(concurrency_target.clone()))
+            .unwrap();
+        registry.register(Box::new(jobs_running.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+
+        Metrics {
+            registry,
+            forge_run_duration_seconds,
+            forge_outcomes_total,
+            forge_peak_memory_bytes,
+            db_insert_duration_seconds,
+            db_insert_retries_total,
+            validation_violations_total,
+            concurrency_target,
+            jobs_running,
+            queue_depth,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            warn!("Error encoding metrics: {}", e);
+        }
+        String::from_utf8_lossy(&buf).to_string()
+    }
+}
+
+// Serves GET /metrics in the Prometheus text format, runs until aborted
+pub async fn serve_metrics(addr: SocketAddr) -> Result<(), Box<dyn StdError>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                warn!("Error serving metrics: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Result<(), Box<dyn StdError>> {
+    // The request line is all we need, scrapers send small requests
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, content_type, body) = if path == "/metrics" {
+        (
+            "200 OK",
+            TextEncoder::new().format_type().to_string(),
+            METRICS.encode(),
+        )
+    } else {
+        (
+            "404 Not Found",
+            "text/plain".to_string(),
+            "Not found\n".to_string(),
+        )
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        METRICS
+            .forge_outcomes_total
+            .with_label_values(&["timeout"])
+            .inc();
+
+        let text = METRICS.encode();
+        assert!(
+            text.contains("liquidations_aggregator_forge_outcomes_total{outcome=\"timeout\"} 1")
+        );
+        assert!(text.contains("# TYPE liquidations_aggregator_forge_peak_memory_bytes histogram"));
+    }
+}
@@ -0,0 +1,268 @@
+use {
+    crate::selection::*,
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt::Write as _,
+    },
+};
+
+// (first block, its timestamp, block time in ms) from each block time change on
+const BLOCK_TIMES: [(i64, i64, i64); 3] = [
+    // BSC genesis, 2020-08-29 03:24:28 UTC
+    (0, 1598671468, 3000),
+    // Lorentz, 2025-04-29 05:05:00 UTC
+    (48773576, 1745903100, 1500),
+    // Maxwell, 2025-06-30 02:30:00 UTC
+    (52337091, 1751250600, 750),
+];
+
+// Why a liquidation is not tested, in the order the rules are applied.
+// Each liquidation is counted under the first rule that excludes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Exclusion {
+    OutOfRange,
+    NotAMarket,
+    ExcludedRepayVToken,
+    ExcludedTx,
+    ExcludedBorrower,
+    CollateralRule,
+    NotFirstPerBorrower,
+    AlreadyTested,
+}
+
+impl Exclusion {
+    pub const ALL: [Exclusion; 8] = [
+        Exclusion::OutOfRange,
+        Exclusion::NotAMarket,
+        Exclusion::ExcludedRepayVToken,
+        Exclusion::ExcludedTx,
+        Exclusion::ExcludedBorrower,
+        Exclusion::CollateralRule,
+        Exclusion::NotFirstPerBorrower,
+        Exclusion::AlreadyTested,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exclusion::OutOfRange => "out_of_range",
+            Exclusion::NotAMarket => "not_a_market",
+            Exclusion::ExcludedRepayVToken => "excluded_repay_v_token",
+            Exclusion::ExcludedTx => "excluded_tx",
+            Exclusion::ExcludedBorrower => "excluded_borrower",
+            Exclusion::CollateralRule => "collateral_rule",
+            Exclusion::NotFirstPerBorrower => "not_first_per_borrower",
+            Exclusion::AlreadyTested => "already_tested",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Exclusion> {
+        Exclusion::ALL.into_iter().find(|e| e.as_str() == s)
+    }
+}
+
+// One liquidation as seen by the selection
+#[derive(Debug, Clone)]
+pub struct PlanRow {
+    pub transaction_hash: String,
+    pub block_number: i64,
+    pub v_token: String,
+    pub borrower: String,
+    pub v_token_collateral: String,
+    pub exclusion: Option<Exclusion>,
+}
+
+pub struct SelectionPlan {
+    pub selected: Vec<PlanRow>,
+    pub excluded: BTreeMap<Exclusion, usize>,
+    pub by_v_token: BTreeMap<String, usize>,
+    pub by_collateral: BTreeMap<String, usize>,
+    pub by_era: BTreeMap<&'static str, usize>,
+    pub by_month: BTreeMap<String, usize>,
+    names: HashMap<String, String>,
+}
+
+impl SelectionPlan {
+    pub fn new(rows: Vec<PlanRow>, markets: &[Market]) -> Self {
+        let names: HashMap<String, String> = markets
+            .iter()
+            .map(|m| (m.v_token.to_lowercase(), m.name.clone()))
+            .collect();
+        let mut plan = SelectionPlan {
+            selected: Vec::new(),
+            excluded: BTreeMap::new(),
+            by_v_token: BTreeMap::new(),
+            by_collateral: BTreeMap::new(),
+            by_era: BTreeMap::new(),
+            by_month: BTreeMap::new(),
+            names,
+        };
+
+        for row in rows {
+            if let Some(exclusion) = row.exclusion {
+                *plan.excluded.entry(exclusion).or_default() += 1;
+                continue;
+            }
+            *plan.by_v_token.entry(plan.name(&row.v_token)).or_default() += 1;
+            *plan
+                .by_collateral
+                .entry(plan.name(&row.v_token_collateral))
+                .or_default() += 1;
+            *plan
+                .by_era
+                .entry(Era::of_block(row.block_number).as_str())
+                .or_default() += 1;
+            *plan
+                .by_month
+                .entry(estimated_month(row.block_number))
+                .or_default() += 1;
+            plan.selected.push(row);
+        }
+        plan.selected.sort_by_key(|row| row.block_number);
+
+        plan
+    }
+
+    // Market name from the selection, the address for anything else
+    fn name(&self, v_token: &str) -> String {
+        self.names
+            .get(&v_token.to_lowercase())
+            .cloned()
+            .unwrap_or_else(|| v_token.to_string())
+    }
+
+    pub fn print(&self) {
+        let print_counts = |title: &str, counts: &mut dyn Iterator<Item = (String, usize)>| {
+            println!("{}:", title);
+            for (key, count) in counts {
+                println!("  {:<44} {:>7}", key, count);
+            }
+        };
+
+        print_counts(
+            "Excluded",
+            &mut Exclusion::ALL
+                .iter()
+                .map(|e| (e.as_str().to_string(), *self.excluded.get(e).unwrap_or(&0))),
+        );
+        print_counts(
+            "Per repaid vToken",
+            &mut self.by_v_token.iter().map(|(k, v)| (k.clone(), *v)),
+        );
+        print_counts(
+            "Per collateral",
+            &mut self.by_collateral.iter().map(|(k, v)| (k.clone(), *v)),
+        );
+        print_counts(
+            "Per era",
+            &mut self.by_era.iter().map(|(k, v)| (k.to_string(), *v)),
+        );
+        print_counts(
+            "Per month (estimated from the block number)",
+            &mut self.by_month.iter().map(|(k, v)| (k.clone(), *v)),
+        );
+        println!("{} liquidations selected", self.selected.len());
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "transaction_hash,block_number,era,estimated_month,v_token,v_token_name,borrower,v_token_collateral,v_token_collateral_name\n",
+        );
+        for row in &self.selected {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{}",
+                row.transaction_hash,
+                row.block_number,
+                Era::of_block(row.block_number).as_str(),
+                estimated_month(row.block_number),
+                row.v_token,
+                self.name(&row.v_token),
+                row.borrower,
+                row.v_token_collateral,
+                self.name(&row.v_token_collateral),
+            );
+        }
+        csv
+    }
+}
+
+// YYYY-MM the block was probably mined in, there is no block timestamp in venus_liquidations
+pub fn estimated_month(block_number: i64) -> String {
+    let (first_block, first_timestamp, block_time_ms) = BLOCK_TIMES
+        .into_iter()
+        .rev()
+        .find(|(first_block, _, _)| *first_block <= block_number)
+        .unwrap_or(BLOCK_TIMES[0]);
+    let timestamp = first_timestamp + (block_number - first_block) * block_time_ms / 1000;
+    let (year, month, _) = civil_from_days(timestamp.div_euclid(86400));
+    format!("{:04}-{:02}", year, month)
+}
+
+// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection_plan() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(estimated_month(0), "2020-08");
+        // Cancun (Haber) was activated on 2024-06-20
+        assert_eq!(estimated_month(39769787), "2024-06");
+        assert_eq!(estimated_month(48773575), "2025-04");
+        // 3 weeks of 0.75s blocks after Maxwell
+        assert_eq!(estimated_month(52337091 + 2_419_200), "2025-07");
+
+        let row = |tx: &str, block_number, exclusion| PlanRow {
+            transaction_hash: tx.to_string(),
+            block_number,
+            v_token: "0xA07c5b74C9B40447a954e1466938b865b6BBea36".to_string(),
+            borrower: "0xb0b".to_string(),
+            v_token_collateral: "0xc0c".to_string(),
+            exclusion,
+        };
+        let markets = [Market {
+            name: "vBNB".to_string(),
+            v_token: "0xa07c5b74c9b40447a954e1466938b865b6bbea36".to_string(),
+        }];
+        let plan = SelectionPlan::new(
+            vec![
+                row("0x2", 39769788, None),
+                row("0x1", 35490444, None),
+                row("0x3", 1, Some(Exclusion::CollateralRule)),
+                row("0x4", 2, Some(Exclusion::CollateralRule)),
+            ],
+            &markets,
+        );
+
+        assert_eq!(plan.excluded[&Exclusion::CollateralRule], 2);
+        assert_eq!(plan.by_v_token["vBNB"], 2);
+        assert_eq!(plan.by_collateral["0xc0c"], 2);
+        assert_eq!(plan.by_era["shanghai"], 1);
+        assert_eq!(
+            Exclusion::parse("already_tested"),
+            Some(Exclusion::AlreadyTested)
+        );
+
+        let csv = plan.to_csv();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("0x1,35490444,shanghai,"));
+    }
+}
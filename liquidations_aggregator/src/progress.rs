@@ -0,0 +1,306 @@
+use {
+    crate::{failures::*, job_queue::*, metrics::*},
+    serde::Serialize,
+    std::{
+        collections::{BTreeMap, HashMap},
+        error::Error as StdError,
+        fs,
+        path::PathBuf,
+        sync::Mutex,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+};
+
+// Every run writes its summary (and later anything else it produces) to runs/<run id>
+pub const RUNS_DIR: &str = "runs";
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+const SLOWEST_COUNT: usize = 10;
+
+pub fn new_run_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}-{}", now.as_secs(), std::process::id())
+}
+
+pub fn run_dir(run_id: &str) -> PathBuf {
+    PathBuf::from(RUNS_DIR).join(run_id)
+}
+
+// What happened to one leased job
+pub struct JobOutcome {
+    pub state: JobState,
+    pub error: Option<String>,
+    pub category: Option<FailureCategory>, // Of the last failed forge attempt
+    pub simulation_time: Duration,         // Forge runs including retries, zero if forge never ran
+    pub waited_for_block: bool,            // Ran after another liquidation of the same block
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SlowTx {
+    pub transaction_hash: String,
+    pub state: &'static str,
+    pub simulation_secs: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub worker_id: String,
+    pub started_at: u64, // Unix seconds
+    pub elapsed_secs: f64,
+    pub queued: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub interrupted: u64,
+    pub waited_for_block: u64,
+    pub failures_by_category: BTreeMap<&'static str, u64>,
+    pub total_simulation_secs: f64,
+    pub per_minute: f64,
+    pub slowest: Vec<SlowTx>,
+}
+
+#[derive(Default)]
+struct Counts {
+    running: u64,
+    succeeded: u64,
+    failed: u64,
+    skipped: u64,
+    interrupted: u64,
+    waited_for_block: u64,
+    failures_by_category: HashMap<FailureCategory, u64>,
+    simulation_time: Duration,
+    slowest: Vec<SlowTx>,
+}
+
+impl Counts {
+    fn done(&self) -> u64 {
+        self.succeeded + self.failed + self.skipped + self.interrupted
+    }
+}
+
+// Counts the jobs of this process. Other processes working on the same queue are not
+// included, so with several of them the ETA is only a rough upper bound.
+pub struct Progress {
+    run_id: String,
+    worker_id: String,
+    queued: u64,
+    started: Instant,
+    started_at: SystemTime,
+    counts: Mutex<Counts>,
+}
+
+impl Progress {
+    pub fn new(run_id: &str, worker_id: &str, queued: u64) -> Self {
+        Progress {
+            run_id: run_id.to_string(),
+            worker_id: worker_id.to_string(),
+            queued,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    pub fn start(&self) {
+        self.counts.lock().unwrap().running += 1;
+        METRICS.jobs_running.inc();
+    }
+
+    pub fn finish(&self, transaction_hash: &str, outcome: &JobOutcome) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.running = counts.running.saturating_sub(1);
+        METRICS.jobs_running.dec();
+        match outcome.state {
+            JobState::Succeeded => counts.succeeded += 1,
+            JobState::Failed => counts.failed += 1,
+            JobState::Skipped => counts.skipped += 1,
+            JobState::Interrupted => counts.interrupted += 1,
+            JobState::Pending | JobState::Leased => {}
+        }
+        if outcome.waited_for_block {
+            counts.waited_for_block += 1;
+        }
+        if outcome.state == JobState::Failed {
+            let category = outcome.category.unwrap_or(FailureCategory::Unknown);
+            *counts.failures_by_category.entry(category).or_default() += 1;
+        }
+
+        if outcome.simulation_time.is_zero() {
+            return;
+        }
+        counts.simulation_time += outcome.simulation_time;
+        counts.slowest.push(SlowTx {
+            transaction_hash: transaction_hash.to_string(),
+            state: outcome.state.as_str(),
+            simulation_secs: outcome.simulation_time.as_secs_f64(),
+        });
+        counts
+            .slowest
+            .sort_by(|a, b| b.simulation_secs.total_cmp(&a.simulation_secs));
+        counts.slowest.truncate(SLOWEST_COUNT);
+    }
+
+    pub fn report(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        let elapsed = self.started.elapsed();
+        let done = counts.done();
+        let eta = match eta(done, self.queued, elapsed) {
+            Some(eta) => format_duration(eta),
+            None => "unknown".to_string(),
+        };
+        format!(
+            "Progress: {}/{} done ({} succeeded, {} failed, {} skipped), {} running, {:.1}/min, ETA {}",
+            done,
+            self.queued,
+            counts.succeeded,
+            counts.failed,
+            counts.skipped,
+            counts.running,
+            per_minute(done, elapsed),
+            eta
+        )
+    }
+
+    pub fn summary(&self) -> RunSummary {
+        let counts = self.counts.lock().unwrap();
+        let elapsed = self.started.elapsed();
+        RunSummary {
+            run_id: self.run_id.clone(),
+            worker_id: self.worker_id.clone(),
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            elapsed_secs: elapsed.as_secs_f64(),
+            queued: self.queued,
+            succeeded: counts.succeeded,
+            failed: counts.failed,
+            skipped: counts.skipped,
+            interrupted: counts.interrupted,
+            waited_for_block: counts.waited_for_block,
+            failures_by_category: counts
+                .failures_by_category
+                .iter()
+                .map(|(category, count)| (category.as_str(), *count))
+                .collect(),
+            total_simulation_secs: counts.simulation_time.as_secs_f64(),
+            per_minute: per_minute(counts.done(), elapsed),
+            slowest: counts.slowest.clone(),
+        }
+    }
+}
+
+impl RunSummary {
+    pub fn print(&self) {
+        println!(
+            "Run {} finished in {}",
+            self.run_id,
+            format_duration(Duration::from_secs_f64(self.elapsed_secs))
+        );
+        println!("Queued:      {}", self.queued);
+        println!("Succeeded:   {}", self.succeeded);
+        println!("Failed:      {}", self.failed);
+        for (category, count) in &self.failures_by_category {
+            println!("  {:<22} {}", category, count);
+        }
+        println!("Skipped:     {}", self.skipped);
+        println!("Interrupted: {}", self.interrupted);
+        println!(
+            "Waited for a same-block liquidation: {}",
+            self.waited_for_block
+        );
+        println!(
+            "Simulation time: {} ({:.1} liquidations/min)",
+            format_duration(Duration::from_secs_f64(self.total_simulation_secs)),
+            self.per_minute
+        );
+        if !self.slowest.is_empty() {
+            println!("Slowest:");
+            for tx in &self.slowest {
+                println!(
+                    "  {} {:>9} {}",
+                    tx.transaction_hash,
+                    format_duration(Duration::from_secs_f64(tx.simulation_secs)),
+                    tx.state
+                );
+            }
+        }
+    }
+
+    // Returns the path written to
+    pub fn write(&self) -> Result<PathBuf, Box<dyn StdError>> {
+        let dir = run_dir(&self.run_id);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("summary.json");
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
+
+fn per_minute(done: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    done as f64 / elapsed.as_secs_f64() * 60.0
+}
+
+fn eta(done: u64, queued: u64, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let left = queued.saturating_sub(done);
+    Some(elapsed.mul_f64(left as f64 / done as f64))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        assert_eq!(eta(0, 100, Duration::from_secs(60)), None);
+        assert_eq!(
+            eta(25, 100, Duration::from_secs(60)),
+            Some(Duration::from_secs(180))
+        );
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h 2m");
+
+        let progress = Progress::new("run", "worker", 3);
+        for (tx, state, category, secs) in [
+            ("0x1", JobState::Succeeded, None, 5),
+            ("0x2", JobState::Failed, Some(FailureCategory::Timeout), 60),
+            ("0x3", JobState::Failed, Some(FailureCategory::Timeout), 30),
+        ] {
+            progress.start();
+            progress.finish(
+                tx,
+                &JobOutcome {
+                    state,
+                    error: None,
+                    category,
+                    simulation_time: Duration::from_secs(secs),
+                    waited_for_block: tx == "0x3",
+                },
+            );
+        }
+
+        let summary = progress.summary();
+        assert_eq!((summary.succeeded, summary.failed), (1, 2));
+        assert_eq!(summary.waited_for_block, 1);
+        assert_eq!(summary.failures_by_category["timeout"], 2);
+        assert_eq!(summary.total_simulation_secs, 95.0);
+        assert_eq!(summary.slowest[0].transaction_hash, "0x2");
+    }
+}
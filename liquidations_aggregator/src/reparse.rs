@@ -0,0 +1,150 @@
+use {
+    crate::{archive::*, forge::*},
+    clap::ValueEnum,
+    serde_json::Value,
+    std::{
+        collections::{BTreeMap, BTreeSet},
+        error::Error as StdError,
+        path::PathBuf,
+    },
+};
+
+// Where reparse reads the forge output from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReparseSource {
+    // The complete stdout of the latest archived run of each transaction
+    Archive,
+    // The raw strategy lines stored with the results, for results tested before the archive
+    Stored,
+}
+
+pub enum ReparseInput {
+    Archived(PathBuf, ArchiveEntry),
+    Stored(String),
+}
+
+impl ReparseInput {
+    // Archived output is only read when its turn comes, all of it does not fit in memory
+    pub fn load(self) -> Result<ForgeOutput, Box<dyn StdError>> {
+        match self {
+            ReparseInput::Archived(dir, entry) => read_output(&dir, &entry),
+            ReparseInput::Stored(stdout) => Ok(ForgeOutput {
+                stdout,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+// What a reparse changed compared to the results in the database
+#[derive(Debug, Default)]
+pub struct ReparseDiff {
+    pub unchanged: usize,
+    pub changed: usize,
+    pub added: usize, // No results were stored yet
+    pub failed: usize,
+    // Changed liquidations per field, like `repeatLiquidation.profit_usd` or `drainLiquidation.liquidations[].gas_used`
+    pub changed_fields: BTreeMap<String, usize>,
+}
+
+impl ReparseDiff {
+    pub fn record(&mut self, stored: Option<&Value>, reparsed: &Value) {
+        let Some(stored) = stored else {
+            self.added += 1;
+            return;
+        };
+
+        let mut fields = BTreeSet::new();
+        changed_fields(String::new(), stored, reparsed, &mut fields);
+        if fields.is_empty() {
+            self.unchanged += 1;
+            return;
+        }
+        self.changed += 1;
+        for field in fields {
+            *self.changed_fields.entry(field).or_default() += 1;
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Unchanged: {}", self.unchanged);
+        println!("Changed:   {}", self.changed);
+        println!("Added:     {}", self.added);
+        println!("Failed:    {}", self.failed);
+        if !self.changed_fields.is_empty() {
+            println!("Changed fields:");
+            for (field, count) in &self.changed_fields {
+                println!("  {:<60} {:>7}", field, count);
+            }
+        }
+    }
+}
+
+// Paths of the leaves that differ, array elements are merged under `[]`
+fn changed_fields(path: String, stored: &Value, reparsed: &Value, fields: &mut BTreeSet<String>) {
+    let join = |key: &str| match path.as_str() {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    };
+
+    match (stored, reparsed) {
+        (Value::Object(stored), Value::Object(reparsed)) => {
+            let keys: BTreeSet<&String> = stored.keys().chain(reparsed.keys()).collect();
+            for key in keys {
+                match (stored.get(key), reparsed.get(key)) {
+                    (Some(stored), Some(reparsed)) => {
+                        changed_fields(join(key), stored, reparsed, fields)
+                    }
+                    _ => {
+                        fields.insert(join(key));
+                    }
+                }
+            }
+        }
+        (Value::Array(stored), Value::Array(reparsed)) => {
+            let path = format!("{}[]", path);
+            if stored.len() != reparsed.len() {
+                fields.insert(path.clone());
+            }
+            for (stored, reparsed) in stored.iter().zip(reparsed) {
+                changed_fields(path.clone(), stored, reparsed, fields);
+            }
+        }
+        _ if stored != reparsed => {
+            fields.insert(path);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::json};
+
+    #[test]
+    fn test_reparse_diff() {
+        let stored = json!({
+            "repeat": {"profit_usd": "1.5", "liquidations": [{"gas_used": "100"}], "raw": ["a"]},
+            "drain": {"profit_usd": "2.0", "liquidations": []},
+        });
+        let reparsed = json!({
+            "repeat": {"profit_usd": "1.5", "liquidations": [{"gas_used": "120"}], "raw": ["a"]},
+            "drain": {"profit_usd": "2.5", "liquidations": [], "gas_price": "3"},
+        });
+
+        let mut diff = ReparseDiff::default();
+        diff.record(Some(&stored), &stored);
+        diff.record(Some(&stored), &reparsed);
+        diff.record(None, &reparsed);
+
+        assert_eq!((diff.unchanged, diff.changed, diff.added), (1, 1, 1));
+        assert_eq!(
+            diff.changed_fields.keys().collect::<Vec<_>>(),
+            [
+                "drain.gas_price",
+                "drain.profit_usd",
+                "repeat.liquidations[].gas_used"
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use {
+    crate::failures::*,
+    rand::Rng,
+    serde::Deserialize,
+    std::{collections::HashMap, time::Duration},
+};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RetryRule {
+    pub max_attempts: u32, // Including the first run
+    pub base_delay_secs: f64,
+    pub max_delay_secs: f64,
+}
+
+impl RetryRule {
+    pub const NONE: RetryRule = RetryRule {
+        max_attempts: 1,
+        base_delay_secs: 0.0,
+        max_delay_secs: 0.0,
+    };
+}
+
+// Retries per failure category. Only transient categories are retried unless the
+// config says otherwise, a reverted assertion fails the same way every time.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(try_from = "HashMap<FailureCategory, RetryRule>")]
+pub struct RetryPolicy {
+    rules: HashMap<FailureCategory, RetryRule>,
+}
+
+// Delays become a Duration, which can't be negative, NaN or infinite
+impl TryFrom<HashMap<FailureCategory, RetryRule>> for RetryPolicy {
+    type Error = String;
+
+    fn try_from(rules: HashMap<FailureCategory, RetryRule>) -> Result<Self, Self::Error> {
+        for (category, rule) in &rules {
+            for (name, secs) in [
+                ("base_delay_secs", rule.base_delay_secs),
+                ("max_delay_secs", rule.max_delay_secs),
+            ] {
+                if !secs.is_finite() || secs < 0.0 || secs >= Duration::MAX.as_secs_f64() {
+                    return Err(format!(
+                        "retry.{}.{} must be a non-negative number of seconds, not {}",
+                        category, name, secs
+                    ));
+                }
+            }
+        }
+        Ok(RetryPolicy { rules })
+    }
+}
+
+impl RetryPolicy {
+    pub fn rule(&self, category: FailureCategory) -> RetryRule {
+        if let Some(rule) = self.rules.get(&category) {
+            return *rule;
+        }
+        match category {
+            FailureCategory::RpcRateLimit => RetryRule {
+                max_attempts: 5,
+                base_delay_secs: 10.0,
+                max_delay_secs: 300.0,
+            },
+            FailureCategory::ForkFetchError => RetryRule {
+                max_attempts: 3,
+                base_delay_secs: 5.0,
+                max_delay_secs: 120.0,
+            },
+            _ => RetryRule::NONE,
+        }
+    }
+
+    // How long to wait before running again after `attempt` failed, None to give up.
+    // Exponential backoff with jitter, so workers hit by the same rate limit spread out.
+    pub fn backoff(&self, category: FailureCategory, attempt: u32) -> Option<Duration> {
+        let rule = self.rule(category);
+        if attempt >= rule.max_attempts {
+            return None;
+        }
+
+        let exponential = rule.base_delay_secs * 2f64.powi(attempt.saturating_sub(1) as i32);
+        let delay = exponential.min(rule.max_delay_secs);
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        Some(Duration::from_secs_f64(delay * jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy: RetryPolicy = toml::from_str(
+            "[out_of_gas]\nmax_attempts = 2\nbase_delay_secs = 1\nmax_delay_secs = 1",
+        )
+        .unwrap();
+
+        assert_eq!(policy.backoff(FailureCategory::AssertionFailed, 1), None);
+        assert!(policy.backoff(FailureCategory::OutOfGas, 1).unwrap() <= Duration::from_secs(1));
+        assert_eq!(policy.backoff(FailureCategory::OutOfGas, 2), None);
+
+        // 10s doubled three times
+        let delay = policy.backoff(FailureCategory::RpcRateLimit, 4).unwrap();
+        assert!(delay >= Duration::from_secs(40) && delay <= Duration::from_secs(80));
+        assert_eq!(policy.backoff(FailureCategory::RpcRateLimit, 5), None);
+
+        for invalid in ["-1", "nan", "inf"] {
+            let config = format!(
+                "[out_of_gas]\nmax_attempts = 2\nbase_delay_secs = {}\nmax_delay_secs = 1",
+                invalid
+            );
+            assert!(toml::from_str::<RetryPolicy>(&config).is_err());
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use {
+    serde::Deserialize,
+    std::{error::Error as StdError, fs, path::Path},
+};
+
+// BSC hardforks the forge tests are run separately for
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Era {
+    Istanbul,
+    Berlin,
+    Shanghai,
+    Cancun,
+}
+
+impl Era {
+    pub const ALL: [Era; 4] = [Era::Istanbul, Era::Berlin, Era::Shanghai, Era::Cancun];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Era::Istanbul => "istanbul",
+            Era::Berlin => "berlin",
+            Era::Shanghai => "shanghai",
+            Era::Cancun => "cancun",
+        }
+    }
+
+    pub fn first_block(self) -> Option<i64> {
+        match self {
+            Era::Istanbul => None,
+            Era::Berlin => Some(31302048),
+            Era::Shanghai => Some(35490444),
+            Era::Cancun => Some(39769787),
+        }
+    }
+
+    // Exclusive
+    pub fn last_block(self) -> Option<i64> {
+        match self {
+            Era::Istanbul => Era::Berlin.first_block(),
+            Era::Berlin => Era::Shanghai.first_block(),
+            Era::Shanghai => Era::Cancun.first_block(),
+            Era::Cancun => None,
+        }
+    }
+
+    pub fn of_block(block_number: i64) -> Era {
+        Era::ALL
+            .into_iter()
+            .rev()
+            .find(|era| era.first_block().is_none_or(|first| block_number >= first))
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Market {
+    pub name: String,
+    pub v_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExcludedMarket {
+    pub v_token: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExcludedTx {
+    pub transaction_hash: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExcludedBorrower {
+    pub borrower: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockRange {
+    pub from: Option<i64>,
+    pub to: Option<i64>, // Exclusive
+}
+
+// Skips liquidations seizing `v_token_collateral` before the given block or era
+#[derive(Debug, Deserialize)]
+pub struct CollateralRule {
+    pub v_token_collateral: String,
+    pub before_block: Option<i64>,
+    pub before_era: Option<Era>,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SelectionConfig {
+    pub era: Option<Era>,
+    pub block_range: Option<BlockRange>, // Takes precedence over `era`
+    pub markets: Vec<Market>,
+    #[serde(default)]
+    pub excluded_repay_v_tokens: Vec<ExcludedMarket>,
+    #[serde(default)]
+    pub excluded_txs: Vec<ExcludedTx>,
+    #[serde(default)]
+    pub excluded_borrowers: Vec<ExcludedBorrower>,
+    #[serde(default)]
+    pub collateral_rules: Vec<CollateralRule>,
+}
+
+// Parameters of the selection query in db_client, in placeholder order
+pub struct SelectionQuery {
+    pub markets: Vec<String>,
+    pub excluded_repay_v_tokens: Vec<String>,
+    pub excluded_txs: Vec<String>,
+    pub excluded_borrowers: Vec<String>,
+    pub rule_collaterals: Vec<String>,
+    pub rule_before_blocks: Vec<i64>,
+    pub from_block: Option<i64>,
+    pub to_block: Option<i64>,
+}
+
+impl SelectionConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn StdError>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read selection {}: {}", path.display(), e))?;
+        let config = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse selection {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    pub fn compile(&self) -> Result<SelectionQuery, Box<dyn StdError>> {
+        let (from_block, to_block) = match (&self.block_range, self.era) {
+            (Some(range), _) => (range.from, range.to),
+            (None, Some(era)) => (era.first_block(), era.last_block()),
+            (None, None) => (None, None),
+        };
+
+        let mut rule_collaterals = Vec::new();
+        let mut rule_before_blocks = Vec::new();
+        for rule in &self.collateral_rules {
+            let before_block = match (rule.before_block, rule.before_era) {
+                (Some(block), None) => block,
+                // Nothing comes before the first era
+                (None, Some(era)) => era.first_block().unwrap_or(0),
+                _ => {
+                    return Err(format!(
+                        "Collateral rule for {} needs exactly one of before_block and before_era",
+                        rule.v_token_collateral
+                    )
+                    .into())
+                }
+            };
+            rule_collaterals.push(rule.v_token_collateral.clone());
+            rule_before_blocks.push(before_block);
+        }
+
+        Ok(SelectionQuery {
+            markets: self.markets.iter().map(|m| m.v_token.clone()).collect(),
+            excluded_repay_v_tokens: self
+                .excluded_repay_v_tokens
+                .iter()
+                .map(|m| m.v_token.clone())
+                .collect(),
+            excluded_txs: self
+                .excluded_txs
+                .iter()
+                .map(|tx| tx.transaction_hash.clone())
+                .collect(),
+            excluded_borrowers: self
+                .excluded_borrowers
+                .iter()
+                .map(|b| b.borrower.clone())
+                .collect(),
+            rule_collaterals,
+            rule_before_blocks,
+            from_block,
+            to_block,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_selection_compiles() {
+        let config: SelectionConfig = toml::from_str(include_str!("../selection.toml")).unwrap();
+        let query = config.compile().unwrap();
+
+        assert_eq!(query.from_block, Era::Cancun.first_block());
+        assert_eq!(query.to_block, None);
+        assert_eq!(query.rule_collaterals.len(), query.rule_before_blocks.len());
+        assert_eq!(query.rule_before_blocks, vec![31302048]);
+        assert_eq!(Era::of_block(35490443), Era::Berlin);
+    }
+}
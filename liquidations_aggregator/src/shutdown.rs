@@ -0,0 +1,96 @@
+use {
+    serde::Deserialize,
+    std::time::Duration,
+    tokio::signal,
+    tokio_util::sync::CancellationToken,
+    tracing::{error, warn},
+};
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    // How long running forge tests may finish after SIGINT/SIGTERM before they are killed
+    pub grace_period_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period_secs: 120,
+        }
+    }
+}
+
+// Two stages: `stop` ends dispatching of new jobs, `kill` ends the forge tests still running
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    stop: CancellationToken,
+    kill: CancellationToken,
+}
+
+impl Shutdown {
+    // Stops on the first SIGINT/SIGTERM and kills after the grace period or on the second one
+    pub fn listen(config: &ShutdownConfig) -> Self {
+        let shutdown = Shutdown::default();
+        let grace_period = Duration::from_secs(config.grace_period_secs);
+
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                wait_for_signal().await;
+                warn!(
+                    ?grace_period,
+                    "Shutting down, waiting for running forge tests (signal again to kill them now)"
+                );
+                shutdown.stop.cancel();
+
+                tokio::select! {
+                    _ = tokio::time::sleep(grace_period) => {}
+                    _ = wait_for_signal() => {}
+                }
+                warn!("Killing running forge tests");
+                shutdown.kill.cancel();
+            }
+        });
+
+        shutdown
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    pub async fn stopping(&self) {
+        self.stop.cancelled().await
+    }
+
+    pub async fn killing(&self) {
+        self.kill.cancelled().await
+    }
+
+    #[cfg(test)]
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    let mut sigterm = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            error!("Error listening for SIGTERM: {}", e);
+            let _ = signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = signal::ctrl_c().await;
+}
@@ -0,0 +1,203 @@
+use {
+    crate::{archive::*, big_num::*, db_client::*, failures::*, forge::*, shutdown::*},
+    async_trait::async_trait,
+    clap::ValueEnum,
+    std::{collections::HashMap, error::Error as StdError, path::PathBuf},
+};
+
+// The liquidation to replay, what the Solidity test reads from its environment
+#[derive(Debug, Clone)]
+pub struct SimulationRequest {
+    pub transaction_hash: String,
+    pub block_number: i64,
+    pub repay_v_token: String,
+    pub borrower: String,
+    pub repay_amount: U256,
+    pub collateral_v_token: String,
+    pub expected_seize: U256,
+    pub gas_price: U256,
+}
+
+impl From<&LiquidationData> for SimulationRequest {
+    fn from(data: &LiquidationData) -> Self {
+        SimulationRequest {
+            transaction_hash: data.transaction_hash.clone(),
+            block_number: data.block_number,
+            repay_v_token: data.v_token.clone(),
+            borrower: data.borrower.clone(),
+            repay_amount: data.repay_amount,
+            collateral_v_token: data.v_token_collateral.clone(),
+            expected_seize: data.seize_tokens,
+            gas_price: data.gas_price,
+        }
+    }
+}
+
+// The output of a completed simulation, or why it did not complete
+pub type SimulationOutcome = Result<ForgeOutput, ForgeFailure>;
+
+#[async_trait]
+pub trait Simulator: Send + Sync {
+    fn name(&self) -> &'static str;
+    // Ends with an interrupted failure once the shutdown starts killing
+    async fn simulate(&self, request: &SimulationRequest, shutdown: &Shutdown)
+        -> SimulationOutcome;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SimulatorKind {
+    // Runs the forge tests against a BSC fork
+    Forge,
+    // Returns the archived output of an earlier run, without forking BSC
+    Replay,
+}
+
+pub fn simulator(
+    kind: SimulatorKind,
+    limits: &ForgeLimits,
+) -> Result<Box<dyn Simulator>, Box<dyn StdError>> {
+    Ok(match kind {
+        SimulatorKind::Forge => Box::new(ForgeSimulator::new(limits.clone())?),
+        SimulatorKind::Replay => Box::new(ReplaySimulator::from_archive()?),
+    })
+}
+
+// Replays the latest archived output of each transaction forge completed
+pub struct ReplaySimulator {
+    outputs: HashMap<String, (PathBuf, ArchiveEntry)>,
+}
+
+impl ReplaySimulator {
+    pub fn from_archive() -> Result<Self, Box<dyn StdError>> {
+        let outputs = latest_archived_outputs(&[])?
+            .into_iter()
+            .map(|(dir, entry)| (entry.transaction_hash.clone(), (dir, entry)))
+            .collect();
+        Ok(ReplaySimulator { outputs })
+    }
+}
+
+#[async_trait]
+impl Simulator for ReplaySimulator {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    async fn simulate(
+        &self,
+        request: &SimulationRequest,
+        _shutdown: &Shutdown,
+    ) -> SimulationOutcome {
+        let Some((dir, entry)) = self.outputs.get(&request.transaction_hash).cloned() else {
+            return Err(ForgeFailure::new(
+                FailureCategory::Unknown,
+                "No archived forge output to replay".to_string(),
+            ));
+        };
+
+        tokio::task::spawn_blocking(move || read_output(&dir, &entry).map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|output| output)
+            .map_err(|e| {
+                ForgeFailure::new(
+                    FailureCategory::Unknown,
+                    format!("Failed to read archived forge output: {}", e),
+                )
+            })
+    }
+}
+
+// Plays back scripted outcomes per transaction, for testing the scheduling without forge
+#[cfg(test)]
+pub struct ScriptedSimulator {
+    script: std::sync::Mutex<HashMap<String, std::collections::VecDeque<SimulationOutcome>>>,
+    delay: std::time::Duration,
+    pub calls: std::sync::Mutex<Vec<String>>,
+    // Blocks of the simulations in progress
+    running: std::sync::Mutex<Vec<i64>>,
+    pub max_running: std::sync::Mutex<usize>,
+    pub same_block_overlapped: std::sync::Mutex<bool>,
+}
+
+#[cfg(test)]
+impl ScriptedSimulator {
+    pub fn new(delay: std::time::Duration) -> Self {
+        ScriptedSimulator {
+            script: Default::default(),
+            delay,
+            calls: Default::default(),
+            running: Default::default(),
+            max_running: Default::default(),
+            same_block_overlapped: Default::default(),
+        }
+    }
+
+    // Outcomes of one transaction are returned in the order they were pushed
+    pub fn push(&self, transaction_hash: &str, outcome: SimulationOutcome) {
+        self.script
+            .lock()
+            .unwrap()
+            .entry(transaction_hash.to_string())
+            .or_default()
+            .push_back(outcome);
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Simulator for ScriptedSimulator {
+    fn name(&self) -> &'static str {
+        "scripted"
+    }
+
+    async fn simulate(
+        &self,
+        request: &SimulationRequest,
+        shutdown: &Shutdown,
+    ) -> SimulationOutcome {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(request.transaction_hash.clone());
+        {
+            let mut running = self.running.lock().unwrap();
+            if running.contains(&request.block_number) {
+                *self.same_block_overlapped.lock().unwrap() = true;
+            }
+            running.push(request.block_number);
+            let mut max_running = self.max_running.lock().unwrap();
+            *max_running = (*max_running).max(running.len());
+        }
+        let killed = tokio::select! {
+            _ = tokio::time::sleep(self.delay) => false,
+            _ = shutdown.killing() => true,
+        };
+        {
+            let mut running = self.running.lock().unwrap();
+            let i = running
+                .iter()
+                .position(|block| *block == request.block_number);
+            running.swap_remove(i.unwrap());
+        }
+        if killed {
+            return Err(ForgeFailure::new(
+                FailureCategory::Interrupted,
+                "Killed on shutdown".to_string(),
+            ));
+        }
+
+        let outcome = self
+            .script
+            .lock()
+            .unwrap()
+            .get_mut(&request.transaction_hash)
+            .and_then(|outcomes| outcomes.pop_front());
+        outcome.unwrap_or_else(|| {
+            Err(ForgeFailure::new(
+                FailureCategory::Unknown,
+                "Nothing scripted".to_string(),
+            ))
+        })
+    }
+}
@@ -0,0 +1,278 @@
+use {
+    crate::{big_num::*, log_parsing::*},
+    serde::Serialize,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    // The values are computed from the same numbers, they can only differ by a bug
+    Error,
+    // Estimates that are close to the reported value but not computed the same way
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+// Numbers of a strategy report that do not agree with each other
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    pub strategy: String,
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+// Per liquidation the collateral is redeemed on its own, the report redeems all of it at once
+const SEIZED_USD_TOLERANCE_PPM: u64 = 1;
+
+pub fn validate(results: &LiquidationTestResults) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for (strategy, report) in &results.strategies {
+        validate_report(report, &mut |check, severity, message| {
+            violations.push(Violation {
+                strategy: strategy.clone(),
+                check,
+                severity,
+                message,
+            })
+        });
+    }
+    violations
+}
+
+fn validate_report(
+    report: &StrategyRunReport,
+    violation: &mut impl FnMut(&'static str, Severity, String),
+) {
+    let gas = &report.gas_usage;
+    // The logged numbers can be anything, an expected value that overflows is a violation too
+    let mut expect_u256 = |check, field: &str, expected: Option<U256>, actual: U256| {
+        let Some(expected) = expected else {
+            let message = format!("Expected {} overflows", field);
+            return violation("overflow", Severity::Error, message);
+        };
+        if expected != actual {
+            let message = format!("{} is {}, expected {}", field, actual, expected);
+            violation(check, Severity::Error, message);
+        }
+    };
+
+    expect_u256(
+        "gas_total",
+        "gas_usage.total",
+        sum_u256([gas.approves, gas.liquidations, gas.redeems].into_iter()),
+        gas.total,
+    );
+    expect_u256(
+        "liquidations_gas",
+        "gas_usage.liquidations",
+        sum_u256(report.liquidations.iter().map(|l| l.gas_used)),
+        gas.liquidations,
+    );
+
+    for asset in &report.assets {
+        let v_token = &asset.initial_data.v_token;
+        let repaid = sum_u256(
+            report
+                .liquidations
+                .iter()
+                .filter(|l| l.repay_v_token.eq_ignore_ascii_case(v_token))
+                .map(|l| l.repay_amount),
+        );
+        let gained = sum_u256(
+            report
+                .liquidations
+                .iter()
+                .filter(|l| l.collateral_v_token.eq_ignore_ascii_case(v_token))
+                .map(|l| l.collateral_v_token_gained),
+        );
+        let field = |name| format!("{} {}", asset.initial_data.symbol, name);
+        expect_u256("asset_repaid", &field("repaid"), repaid, asset.repaid);
+        expect_u256(
+            "asset_collateral_gained",
+            &field("collateralVTokenGained"),
+            gained,
+            asset.collateral_v_token_gained,
+        );
+    }
+
+    let mut expect_usd = |check,
+                          severity,
+                          expected: Option<Decimal18>,
+                          actual: Decimal18,
+                          tolerance: Option<Decimal18>| {
+        let difference = expected.and_then(|expected| expected.checked_sub(actual));
+        let (Some(expected), Some(difference), Some(tolerance)) = (expected, difference, tolerance)
+        else {
+            let message = format!("Expected {} overflows", check);
+            return violation("overflow", Severity::Error, message);
+        };
+        if difference.abs() > tolerance {
+            let message = format!("{} is {}, expected {}", check, actual, expected);
+            violation(check, severity, message);
+        }
+    };
+    let exact = Some(Decimal18::default());
+    // Like the test, USD values are amount * price with the price scaled by 10^18
+    let usd = |amount: U256, price: U256| {
+        let value = amount.checked_mul(price)?;
+        Some(Decimal18::from_wei(value / U256::exp10(18)))
+    };
+
+    expect_usd(
+        "repaid_usd",
+        Severity::Error,
+        sum_decimal18(
+            (report.assets.iter()).map(|asset| usd(asset.repaid, asset.initial_data.price)),
+        ),
+        report.repaid_usd,
+        exact,
+    );
+    expect_usd(
+        "seized_usd",
+        Severity::Error,
+        sum_decimal18(
+            (report.assets.iter())
+                .map(|asset| usd(asset.collateral_underlying_gained, asset.initial_data.price)),
+        ),
+        report.seized_usd,
+        exact,
+    );
+    // gas_price is in wei, like the gas fee the chain coin price converts
+    expect_usd(
+        "gas_fee_usd",
+        Severity::Error,
+        (gas.total.checked_mul(report.gas_price))
+            .and_then(|gas_fee| usd(gas_fee, report.chain_coin_price)),
+        report.gas_fee_usd,
+        exact,
+    );
+    expect_usd(
+        "profit_usd",
+        Severity::Error,
+        (report.seized_usd.checked_sub(report.repaid_usd))
+            .and_then(|profit| profit.checked_sub(report.gas_fee_usd)),
+        report.profit_usd,
+        exact,
+    );
+
+    expect_usd(
+        "liquidations_seized_usd",
+        Severity::Warning,
+        sum_decimal18(report.liquidations.iter().map(|l| Some(l.seized_usd))),
+        report.seized_usd,
+        (report.seized_usd.abs()).checked_mul(Decimal18::from_wei(
+            U256::exp10(12) * SEIZED_USD_TOLERANCE_PPM,
+        )),
+    );
+}
+
+// None on overflow
+fn sum_u256(mut values: impl Iterator<Item = U256>) -> Option<U256> {
+    values.try_fold(U256::zero(), |sum, value| sum.checked_add(value))
+}
+
+// None on overflow or when a value overflowed already
+fn sum_decimal18(mut values: impl Iterator<Item = Option<Decimal18>>) -> Option<Decimal18> {
+    values.try_fold(Decimal18::default(), |sum, value| sum.checked_add(value?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGS: &str = "Tests case: repeatLiquidation
+strategyRunReport start
+asset start 0
+initialData start
+vtoken 0xA
+price 2000000000000000000
+initialData end
+repaid 10
+collateralVTokenGained 5
+collateralUnderlyingGained 20
+asset end
+liquidation start 0
+repayVToken 0xA
+collateralVToken 0xA
+repayAmount 10
+collateralVTokenGained 5
+gasUsed 100
+seizedUsd 0.000000000000000040
+liquidation end
+gasUsage start
+approves 1
+liquidations 100
+redeems 2
+total 103
+gasUsage end
+gasPrice 1000000000
+chainCoinPrice 1000000000000000000
+gasFeeUsd 0.000000103000000000
+repaidUsd 0.000000000000000020
+seizedUsd 0.000000000000000040
+profitUsd -0.000000102999999980
+strategyRunReport end
+Tests case end
+";
+
+    #[test]
+    fn test_validate() {
+        let mut results = parse_logs(LOGS).unwrap();
+        assert_eq!(validate(&results), []);
+
+        let report = &mut results.strategies["repeatLiquidation"];
+        report.gas_usage.total = U256::from(104);
+        report.liquidations[0].seized_usd = "0.000000000000000041".parse().unwrap();
+        let violations = validate(&results);
+        let checks: Vec<(&str, Severity)> = violations
+            .iter()
+            .map(|violation| (violation.check, violation.severity))
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                ("gas_total", Severity::Error),
+                ("gas_fee_usd", Severity::Error),
+                ("liquidations_seized_usd", Severity::Warning),
+            ]
+        );
+        assert_eq!(violations[0].strategy, "repeatLiquidation");
+        assert_eq!(
+            violations[0].message,
+            "gas_usage.total is 104, expected 103"
+        );
+
+        // Numbers too large to check are reported instead of panicking
+        let mut results = parse_logs(LOGS).unwrap();
+        let report = &mut results.strategies["repeatLiquidation"];
+        report.gas_usage.redeems = U256::MAX;
+        report.gas_price = U256::MAX;
+        report.assets[0].initial_data.price = U256::MAX;
+        report.seized_usd = -Decimal18::from_wei(U256::MAX);
+        let violations = validate(&results);
+        let messages: Vec<(&str, &str)> = violations
+            .iter()
+            .map(|violation| (violation.check, violation.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                ("overflow", "Expected gas_usage.total overflows"),
+                ("overflow", "Expected repaid_usd overflows"),
+                ("overflow", "Expected seized_usd overflows"),
+                ("overflow", "Expected gas_fee_usd overflows"),
+                ("overflow", "Expected profit_usd overflows"),
+                ("overflow", "Expected liquidations_seized_usd overflows"),
+            ]
+        );
+    }
+}